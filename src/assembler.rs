@@ -10,7 +10,7 @@ bitflags! {
     }
 }
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, Debug)]
 pub enum X64Register {
     RAX = 0,
@@ -61,6 +61,12 @@ impl<'a> Assembler<'a> {
         self.addr += 1;
     }
 
+    /// Puts raw `bytes` (not an instruction) at current address.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data[self.addr..self.addr + bytes.len()].copy_from_slice(bytes);
+        self.addr += bytes.len();
+    }
+
     fn imm32(&mut self, imm: u32) {
         self.put((imm & 0xFF) as u8);
         self.put(((imm >> 8) & 0xFF) as u8);
@@ -99,6 +105,16 @@ impl<'a> Assembler<'a> {
         self.imm64(imm);
     }
 
+    pub fn mov_reg(&mut self, to: X64Register, from: X64Register) {
+        let rex = Rex::W | if to.is_extended() { Rex::B } else { Rex::empty() };
+        let rex = rex | if from.is_extended() { Rex::R } else { Rex::empty() };
+
+        self.put(rex.bits());
+        self.put(0x89);
+        self.mod_rm(from.to_u8(), 0b11, to.to_u8());
+    }
+
+    #[allow(dead_code)]
     pub fn mul_unsigned(&mut self, reg: X64Register) {
         if reg.is_extended() {
             self.put(Rex::B.bits());
@@ -117,27 +133,34 @@ impl<'a> Assembler<'a> {
         self.mod_rm(5, 0b11, reg.to_u8());
     }
 
-    pub fn mov_indirect(&mut self, memory: X64Register, imm: u8) {
-        if memory.is_extended() {
-            self.put(Rex::B.bits());
+    /// Encodes memory operand `[memory + offset]` using the shortest displacement.
+    fn memory_operand(&mut self, reg_opcode: u8, memory: X64Register, offset: i32) {
+        let rm = memory.to_u8();
+        let r#mod = match offset {
+            0 if rm != 0b101 => 0b00, // [rbp] and [r13] can be encoded only with displacement
+            -128..=127 => 0b01,
+            _ => 0b10,
+        };
+
+        self.mod_rm(reg_opcode, r#mod, rm);
+        if rm == 0b100 {
+            self.sib(4, 0, 4); // [rsp] and [r12] need SIB byte
         }
 
-        self.put(0xC6);
+        match r#mod {
+            0b01 => self.put(offset as u8),
+            0b10 => self.imm32(offset as u32),
+            _ => {}
+        }
+    }
 
-        match memory {
-            X64Register::R12 => {
-                self.mod_rm(0, 0b00, 4);
-                self.sib(4, 0, 4);
-            }
-            X64Register::R13 => {
-                self.mod_rm(0, 0b01, 0b101);
-                self.put(0x00); // +0 (+disp8)
-            }
-            _ => {
-                self.mod_rm(0, 0b00, memory.to_u8());
-            }
+    pub fn mov_indirect(&mut self, memory: X64Register, offset: i32, imm: u8) {
+        if memory.is_extended() {
+            self.put(Rex::B.bits());
         }
 
+        self.put(0xC6);
+        self.memory_operand(0, memory, offset);
         self.put(imm);
     }
 
@@ -159,6 +182,32 @@ impl<'a> Assembler<'a> {
         self.imm32(imm);
     }
 
+    pub fn and(&mut self, reg: X64Register, imm: u32) {
+        let rex = Rex::W | if reg.is_extended() { Rex::B } else { Rex::empty() };
+
+        self.put(rex.bits());
+        self.put(0x81);
+        self.mod_rm(4, 0b11, reg.to_u8());
+        self.imm32(imm);
+    }
+
+    pub fn shr(&mut self, reg: X64Register, imm: u8) {
+        let rex = Rex::W | if reg.is_extended() { Rex::B } else { Rex::empty() };
+
+        self.put(rex.bits());
+        self.put(0xC1);
+        self.mod_rm(5, 0b11, reg.to_u8());
+        self.put(imm);
+    }
+
+    pub fn neg(&mut self, reg: X64Register) {
+        let rex = Rex::W | if reg.is_extended() { Rex::B } else { Rex::empty() };
+
+        self.put(rex.bits());
+        self.put(0xF7);
+        self.mod_rm(3, 0b11, reg.to_u8());
+    }
+
     pub fn push(&mut self, reg: X64Register) {
         if reg.is_extended() {
             self.put(Rex::B.bits());
@@ -175,88 +224,53 @@ impl<'a> Assembler<'a> {
         self.put(0x58 + reg.to_u8());
     }
 
-    fn op_80(&mut self, opcode: u8, memory: X64Register, imm: u8) {
+    fn op_80(&mut self, opcode: u8, memory: X64Register, offset: i32, imm: u8) {
         if memory.is_extended() {
             self.put(Rex::B.bits());
         }
         self.put(0x80);
-        match memory {
-            X64Register::R12 => {
-                self.mod_rm(opcode, 0b00, 4);
-                self.sib(4, 0, 4);
-            }
-            X64Register::R13 => {
-                self.mod_rm(opcode, 0b01, 0b101);
-                self.put(0x00); // +0 (+disp8)
-            }
-            _ => {
-                self.mod_rm(opcode, 0b00, memory.to_u8());
-            }
-        }
+        self.memory_operand(opcode, memory, offset);
         self.put(imm);
     }
 
-    pub fn add_indirect(&mut self, memory: X64Register, imm: u8) {
-        self.op_80(0, memory, imm);
+    pub fn add_indirect(&mut self, memory: X64Register, offset: i32, imm: u8) {
+        self.op_80(0, memory, offset, imm);
     }
 
-    pub fn sub_indirect(&mut self, memory: X64Register, imm: u8) {
-        self.op_80(5, memory, imm);
+    pub fn sub_indirect(&mut self, memory: X64Register, offset: i32, imm: u8) {
+        self.op_80(5, memory, offset, imm);
     }
 
-    pub fn cmp_indirect(&mut self, memory: X64Register, imm: u8) {
-        self.op_80(7, memory, imm);
+    pub fn cmp_indirect(&mut self, memory: X64Register, offset: i32, imm: u8) {
+        self.op_80(7, memory, offset, imm);
     }
 
-    pub fn mov_to_reg(&mut self, to: X64Register, from_memory: X64Register) {
+    pub fn test_indirect(&mut self, memory: X64Register, offset: i32, imm: u8) {
+        if memory.is_extended() {
+            self.put(Rex::B.bits());
+        }
+
+        self.put(0xF6);
+        self.memory_operand(0, memory, offset);
+        self.put(imm);
+    }
+
+    pub fn mov_to_reg(&mut self, to: X64Register, from_memory: X64Register, offset: i32) {
         let rex = Rex::W | if from_memory.is_extended() { Rex::B } else { Rex::empty() };
         let rex = rex | if to.is_extended() { Rex::R } else { Rex::empty() };
 
         self.put(rex.bits());
         self.put(0x0F);
         self.put(0xB6);
-
-        match from_memory {
-            X64Register::R12 => {
-                self.mod_rm(to.to_u8(), 0b00, 4);
-                self.sib(4, 0, 4);
-            }
-            X64Register::R13 => {
-                self.mod_rm(to.to_u8(), 0b01, 0b101);
-                self.put(0x00); // +0 (+disp8)
-            }
-            _ => {
-                self.mod_rm(to.to_u8(), 0b00, from_memory.to_u8());
-            }
-        }
+        self.memory_operand(to.to_u8(), from_memory, offset);
     }
 
+    #[allow(dead_code)]
     pub fn mov_to_memory(&mut self, to_memory: X64Register, from_reg: X64Register) {
-        let rex = if to_memory.is_extended() { Rex::B } else { Rex::empty() };
-        let rex = rex | if from_reg.is_extended() { Rex::R } else { Rex::empty() };
-
-        if !rex.is_empty() {
-            self.put(rex.bits());
-        }
-
-        self.put(0x88);
-
-        match to_memory {
-            X64Register::R12 => {
-                self.mod_rm(from_reg.to_u8(), 0b00, 4);
-                self.sib(4, 0, 4);
-            }
-            X64Register::R13 => {
-                self.mod_rm(from_reg.to_u8(), 0b01, 0b101);
-                self.put(0x00); // +0 (+disp8)
-            }
-            _ => {
-                self.mod_rm(from_reg.to_u8(), 0b00, to_memory.to_u8());
-            }
-        }
+        self.op_to_mem_offset(to_memory, from_reg, 0, 0x88);
     }
 
-    fn op_to_mem_offset(&mut self, to_memory: X64Register, from_reg: X64Register, offset: i8, opcode: u8) {
+    fn op_to_mem_offset(&mut self, to_memory: X64Register, from_reg: X64Register, offset: i32, opcode: u8) {
         let rex = if to_memory.is_extended() { Rex::B } else { Rex::empty() };
         let rex = rex | if from_reg.is_extended() { Rex::R } else { Rex::empty() };
 
@@ -265,25 +279,14 @@ impl<'a> Assembler<'a> {
         }
 
         self.put(opcode);
-
-        match to_memory {
-            X64Register::R12 => {
-                self.mod_rm(from_reg.to_u8(), 0b01, 4);
-                self.sib(to_memory.to_u8(), 0, 4);
-            }
-            _ => {
-                self.mod_rm(from_reg.to_u8(), 0b01, to_memory.to_u8());
-            }
-        }
-
-        self.put(offset as u8);
+        self.memory_operand(from_reg.to_u8(), to_memory, offset);
     }
 
-    pub fn mov_to_mem_offset(&mut self, to_memory: X64Register, from_reg: X64Register, offset: i8) {
+    pub fn mov_to_mem_offset(&mut self, to_memory: X64Register, from_reg: X64Register, offset: i32) {
         self.op_to_mem_offset(to_memory, from_reg, offset, 0x88);
     }
 
-    pub fn add_to_mem_offset(&mut self, to_memory: X64Register, from_reg: X64Register, offset: i8) {
+    pub fn add_to_mem_offset(&mut self, to_memory: X64Register, from_reg: X64Register, offset: i32) {
         self.op_to_mem_offset(to_memory, from_reg, offset, 0x00);
     }
 
@@ -299,12 +302,43 @@ impl<'a> Assembler<'a> {
         self.imm32(relative_addr as u32)
     }
 
+    pub fn jc(&mut self, relative_addr: i32) {
+        self.put(0x0f);
+        self.put(0x82);
+        self.imm32(relative_addr as u32)
+    }
+
+    pub fn jnc(&mut self, relative_addr: i32) {
+        self.put(0x0f);
+        self.put(0x83);
+        self.imm32(relative_addr as u32)
+    }
+
+    pub fn jmp(&mut self, relative_addr: i32) {
+        self.put(0xe9);
+        self.imm32(relative_addr as u32)
+    }
+
+    #[allow(dead_code)]
+    pub fn jc_label(&mut self, label: String) {
+        let label_addr = *self.labels.get(&label).expect("label does not exists") as i32;
+        let relative_addr = label_addr - (self.addr as i32 + 6);
+        self.jc(relative_addr);
+    }
+
+    pub fn jmp_label(&mut self, label: String) {
+        let label_addr = *self.labels.get(&label).expect("label does not exists") as i32;
+        let relative_addr = label_addr - (self.addr as i32 + 5);
+        self.jmp(relative_addr);
+    }
+
     pub fn jne_label(&mut self, label: String) {
         let label_addr = *self.labels.get(&label).expect("label does not exists") as i32;
         let relative_addr = label_addr - (self.addr as i32 + 6);
         self.jne(relative_addr);
     }
 
+    #[allow(dead_code)]
     pub fn je_label(&mut self, label: String) {
         let label_addr = *self.labels.get(&label).expect("label does not exists") as i32;
         let relative_addr = label_addr - (self.addr as i32 + 6);
@@ -404,27 +438,47 @@ mod test {
         asm.addr = 0;
     }
 
+    #[test]
+    fn mov_reg() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        // 48 89 d8                mov    rax,rbx
+        asm.mov_reg(X64Register::RAX, X64Register::RBX);
+        assert_eq!(asm.data[..3], [0x48, 0x89, 0xd8]);
+        asm.addr = 0;
+
+        // 49 89 ce                mov    r14,rcx
+        asm.mov_reg(X64Register::R14, X64Register::RCX);
+        assert_eq!(asm.data[..3], [0x49, 0x89, 0xce]);
+        asm.addr = 0;
+
+        // 4c 89 c0                mov    rax,r8
+        asm.mov_reg(X64Register::RAX, X64Register::R8);
+        assert_eq!(asm.data[..3], [0x4c, 0x89, 0xc0]);
+        asm.addr = 0;
+    }
+
     #[test]
     fn mov_indirect() {
         let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
 
         // c6 02 58                mov    BYTE PTR [rdx],0x58
-        asm.mov_indirect(X64Register::RDX, 0x58);
+        asm.mov_indirect(X64Register::RDX, 0, 0x58);
         assert_eq!(asm.data[..3], [0xc6, 0x02, 0x58]);
         asm.addr = 0;
 
         // 41 c6 00 58             mov    BYTE PTR [r8],0x58
-        asm.mov_indirect(X64Register::R8, 0x58);
+        asm.mov_indirect(X64Register::R8, 0, 0x58);
         assert_eq!(asm.data[..4], [0x41, 0xc6, 0x00, 0x58]);
         asm.addr = 0;
 
         // 41 c6 04 24 58          mov    BYTE PTR [r12],0x58
-        asm.mov_indirect(X64Register::R12, 0x58);
+        asm.mov_indirect(X64Register::R12, 0, 0x58);
         assert_eq!(asm.data[..5], [0x41, 0xc6, 0x04, 0x24, 0x58]);
         asm.addr = 0;
 
         // 41 c6 45 00 58          mov    BYTE PTR [r13+0x0],0x58
-        asm.mov_indirect(X64Register::R13, 0x58);
+        asm.mov_indirect(X64Register::R13, 0, 0x58);
         assert_eq!(asm.data[..5], [0x41, 0xc6, 0x45, 0x00, 0x58]);
         asm.addr = 0;
     }
@@ -564,6 +618,66 @@ mod test {
         asm.addr = 0;
     }
 
+    #[test]
+    fn and() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        // 48 81 e0 3f 00 00 00    and    rax,0x3f
+        asm.and(X64Register::RAX, 0x3f);
+        assert_eq!(asm.data[..7], [0x48, 0x81, 0xe0, 0x3f, 0x00, 0x00, 0x00]);
+        asm.addr = 0;
+
+        // 49 81 e6 ff 00 00 00    and    r14,0xff
+        asm.and(X64Register::R14, 0xff);
+        assert_eq!(asm.data[..7], [0x49, 0x81, 0xe6, 0xff, 0x00, 0x00, 0x00]);
+        asm.addr = 0;
+    }
+
+    #[test]
+    fn shr() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        // 48 c1 e8 03             shr    rax,0x3
+        asm.shr(X64Register::RAX, 3);
+        assert_eq!(asm.data[..4], [0x48, 0xc1, 0xe8, 0x03]);
+        asm.addr = 0;
+
+        // 49 c1 ed 01             shr    r13,0x1
+        asm.shr(X64Register::R13, 1);
+        assert_eq!(asm.data[..4], [0x49, 0xc1, 0xed, 0x01]);
+        asm.addr = 0;
+    }
+
+    #[test]
+    fn neg() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        // 48 f7 d8                neg    rax
+        asm.neg(X64Register::RAX);
+        assert_eq!(asm.data[..3], [0x48, 0xf7, 0xd8]);
+        asm.addr = 0;
+
+        // 49 f7 de                neg    r14
+        asm.neg(X64Register::R14);
+        assert_eq!(asm.data[..3], [0x49, 0xf7, 0xde]);
+        asm.addr = 0;
+    }
+
+    #[test]
+    fn test_indirect() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        // f6 00 01                test   BYTE PTR [rax],0x1
+        asm.test_indirect(X64Register::RAX, 0, 0x01);
+        assert_eq!(asm.data[..3], [0xf6, 0x00, 0x01]);
+        asm.addr = 0;
+
+        // 41 f6 46 fe 07          test   BYTE PTR [r14-0x2],0x7
+        asm.test_indirect(X64Register::R14, -2, 0x07);
+        assert_eq!(asm.data[..5], [0x41, 0xf6, 0x46, 0xfe, 0x07]);
+        asm.addr = 0;
+    }
+
     #[test]
     fn sub() {
         let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
@@ -584,58 +698,108 @@ mod test {
         let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
 
         // 80 02 ab                add    BYTE PTR [rdx],0xab
-        asm.add_indirect(X64Register::RDX, 0xab);
+        asm.add_indirect(X64Register::RDX, 0, 0xab);
         assert_eq!(asm.data[..3], [0x80, 0x02, 0xab]);
         asm.addr = 0;
 
         // 41 80 00 ab             add    BYTE PTR [r8],0xab
-        asm.add_indirect(X64Register::R8, 0xab);
+        asm.add_indirect(X64Register::R8, 0, 0xab);
         assert_eq!(asm.data[..4], [0x41, 0x80, 0x00, 0xab]);
         asm.addr = 0;
 
         // 41 80 04 24 ab          add    BYTE PTR [r12],0xab
-        asm.add_indirect(X64Register::R12, 0xab);
+        asm.add_indirect(X64Register::R12, 0, 0xab);
         assert_eq!(asm.data[..5], [0x41, 0x80, 0x04, 0x24, 0xab]);
         asm.addr = 0;
 
         // 41 80 45 00 ab          add    BYTE PTR [r13+0x0],0xab
-        asm.add_indirect(X64Register::R13, 0xab);
+        asm.add_indirect(X64Register::R13, 0, 0xab);
         assert_eq!(asm.data[..5], [0x41, 0x80, 0x45, 0x00, 0xab]);
         asm.addr = 0;
 
         // 41 80 06 ab             add    BYTE PTR [r14],0xab
-        asm.add_indirect(X64Register::R14, 0xab);
+        asm.add_indirect(X64Register::R14, 0, 0xab);
         assert_eq!(asm.data[..4], [0x41, 0x80, 0x06, 0xab]);
         asm.addr = 0;
     }
 
+    #[test]
+    fn indirect_with_displacement() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        // 41 80 46 10 ab          add    BYTE PTR [r14+0x10],0xab
+        asm.add_indirect(X64Register::R14, 0x10, 0xab);
+        assert_eq!(asm.data[..5], [0x41, 0x80, 0x46, 0x10, 0xab]);
+        asm.addr = 0;
+
+        // 41 80 86 00 fe ff ff ab    add    BYTE PTR [r14-0x200],0xab
+        asm.add_indirect(X64Register::R14, -0x200, 0xab);
+        assert_eq!(asm.data[..8], [0x41, 0x80, 0x86, 0x00, 0xfe, 0xff, 0xff, 0xab]);
+        asm.addr = 0;
+
+        // 41 80 84 24 00 10 00 00 01    add    BYTE PTR [r12+0x1000],0x1
+        asm.add_indirect(X64Register::R12, 0x1000, 0x01);
+        assert_eq!(asm.data[..9], [0x41, 0x80, 0x84, 0x24, 0x00, 0x10, 0x00, 0x00, 0x01]);
+        asm.addr = 0;
+
+        // 41 80 be 80 00 00 00 00    cmp    BYTE PTR [r14+0x80],0x0
+        asm.cmp_indirect(X64Register::R14, 0x80, 0x00);
+        assert_eq!(asm.data[..8], [0x41, 0x80, 0xbe, 0x80, 0x00, 0x00, 0x00, 0x00]);
+        asm.addr = 0;
+
+        // 49 0f b6 46 ff          movzx  rax,BYTE PTR [r14-0x1]
+        asm.mov_to_reg(X64Register::RAX, X64Register::R14, -1);
+        assert_eq!(asm.data[..5], [0x49, 0x0f, 0xb6, 0x46, 0xff]);
+        asm.addr = 0;
+
+        // 41 c6 86 2c 01 00 00 07    mov    BYTE PTR [r14+0x12c],0x7
+        asm.mov_indirect(X64Register::R14, 300, 0x07);
+        assert_eq!(asm.data[..8], [0x41, 0xc6, 0x86, 0x2c, 0x01, 0x00, 0x00, 0x07]);
+        asm.addr = 0;
+
+        // 49 0f b6 86 00 fe ff ff    movzx  rax,BYTE PTR [r14-0x200]
+        asm.mov_to_reg(X64Register::RAX, X64Register::R14, -0x200);
+        assert_eq!(asm.data[..8], [0x49, 0x0f, 0xb6, 0x86, 0x00, 0xfe, 0xff, 0xff]);
+        asm.addr = 0;
+
+        // 41 00 86 2c 01 00 00       add    BYTE PTR [r14+0x12c],al
+        asm.add_to_mem_offset(X64Register::R14, X64Register::RAX, 300);
+        assert_eq!(asm.data[..7], [0x41, 0x00, 0x86, 0x2c, 0x01, 0x00, 0x00]);
+        asm.addr = 0;
+
+        // 41 88 86 00 01 00 00    mov    BYTE PTR [r14+0x100],al
+        asm.mov_to_mem_offset(X64Register::R14, X64Register::RAX, 0x100);
+        assert_eq!(asm.data[..7], [0x41, 0x88, 0x86, 0x00, 0x01, 0x00, 0x00]);
+        asm.addr = 0;
+    }
+
 
     #[test]
     fn sub_indirect() {
         let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
 
         // 80 2a ab                sub    BYTE PTR [rdx],0xab
-        asm.sub_indirect(X64Register::RDX, 0xab);
+        asm.sub_indirect(X64Register::RDX, 0, 0xab);
         assert_eq!(asm.data[..3], [0x80, 0x2a, 0xab]);
         asm.addr = 0;
 
         // 41 80 28 ab             sub    BYTE PTR [r8],0xab
-        asm.sub_indirect(X64Register::R8, 0xab);
+        asm.sub_indirect(X64Register::R8, 0, 0xab);
         assert_eq!(asm.data[..4], [0x41, 0x80, 0x28, 0xab]);
         asm.addr = 0;
 
         // 41 80 2c 24 ab          sub    BYTE PTR [r12],0xab
-        asm.sub_indirect(X64Register::R12, 0xab);
+        asm.sub_indirect(X64Register::R12, 0, 0xab);
         assert_eq!(asm.data[..5], [0x41, 0x80, 0x2c, 0x24, 0xab]);
         asm.addr = 0;
 
         // 41 80 6d 00 ab          sub    BYTE PTR [r13+0x0],0xab
-        asm.sub_indirect(X64Register::R13, 0xab);
+        asm.sub_indirect(X64Register::R13, 0, 0xab);
         assert_eq!(asm.data[..5], [0x41, 0x80, 0x6d, 0x00, 0xab]);
         asm.addr = 0;
 
         // 41 80 2e ab             sub    BYTE PTR [r14],0xab
-        asm.sub_indirect(X64Register::R14, 0xab);
+        asm.sub_indirect(X64Register::R14, 0, 0xab);
         assert_eq!(asm.data[..4], [0x41, 0x80, 0x2e, 0xab]);
         asm.addr = 0;
     }
@@ -645,27 +809,27 @@ mod test {
         let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
 
         // 80 3a ab                cmp    BYTE PTR [rdx],0xab
-        asm.cmp_indirect(X64Register::RDX, 0xab);
+        asm.cmp_indirect(X64Register::RDX, 0, 0xab);
         assert_eq!(asm.data[..3], [0x80, 0x3a, 0xab]);
         asm.addr = 0;
 
         // 41 80 38 ab             cmp    BYTE PTR [r8],0xab
-        asm.cmp_indirect(X64Register::R8, 0xab);
+        asm.cmp_indirect(X64Register::R8, 0, 0xab);
         assert_eq!(asm.data[..4], [0x41, 0x80, 0x38, 0xab]);
         asm.addr = 0;
 
         // 41 80 3c 24 ab          cmp    BYTE PTR [r12],0xab
-        asm.cmp_indirect(X64Register::R12, 0xab);
+        asm.cmp_indirect(X64Register::R12, 0, 0xab);
         assert_eq!(asm.data[..5], [0x41, 0x80, 0x3c, 0x24, 0xab]);
         asm.addr = 0;
 
         // 41 80 7d 00 ab          cmp    BYTE PTR [r13+0x0],0xab
-        asm.cmp_indirect(X64Register::R13, 0xab);
+        asm.cmp_indirect(X64Register::R13, 0, 0xab);
         assert_eq!(asm.data[..5], [0x41, 0x80, 0x7d, 0x00, 0xab]);
         asm.addr = 0;
 
         // 41 80 3e ab             cmp    BYTE PTR [r14],0xab
-        asm.cmp_indirect(X64Register::R14, 0xab);
+        asm.cmp_indirect(X64Register::R14, 0, 0xab);
         assert_eq!(asm.data[..4], [0x41, 0x80, 0x3e, 0xab]);
         asm.addr = 0;
     }
@@ -675,37 +839,37 @@ mod test {
         let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
 
         // 49 0f b6 01             movzx  rax,BYTE PTR [r9]
-        asm.mov_to_reg(X64Register::RAX, X64Register::R9);
+        asm.mov_to_reg(X64Register::RAX, X64Register::R9, 0);
         assert_eq!(asm.data[..4], [0x49, 0x0f, 0xb6, 0x01]);
         asm.addr = 0;
 
         // 48 0f b6 03             movzx  rax,BYTE PTR [rbx]
-        asm.mov_to_reg(X64Register::RAX, X64Register::RBX);
+        asm.mov_to_reg(X64Register::RAX, X64Register::RBX, 0);
         assert_eq!(asm.data[..4], [0x48, 0x0f, 0xb6, 0x03]);
         asm.addr = 0;
 
         // 4d 0f b6 08             movzx  r9,BYTE PTR [r8]
-        asm.mov_to_reg(X64Register::R9, X64Register::R8);
+        asm.mov_to_reg(X64Register::R9, X64Register::R8, 0);
         assert_eq!(asm.data[..4], [0x4d, 0x0f, 0xb6, 0x08]);
         asm.addr = 0;
 
         // 49 0f b6 04 24          movzx  rax,BYTE PTR [r12]
-        asm.mov_to_reg(X64Register::RAX, X64Register::R12);
+        asm.mov_to_reg(X64Register::RAX, X64Register::R12, 0);
         assert_eq!(asm.data[..5], [0x49, 0x0f, 0xb6, 0x04, 0x24]);
         asm.addr = 0;
 
         // 4d 0f b6 20             movzx  r12,BYTE PTR [r8]
-        asm.mov_to_reg(X64Register::R12, X64Register::R8);
+        asm.mov_to_reg(X64Register::R12, X64Register::R8, 0);
         assert_eq!(asm.data[..4], [0x4d, 0x0f, 0xb6, 0x20]);
         asm.addr = 0;
 
         // 49 0f b6 45 00          movzx  rax,BYTE PTR [r13+0x0]
-        asm.mov_to_reg(X64Register::RAX, X64Register::R13);
+        asm.mov_to_reg(X64Register::RAX, X64Register::R13, 0);
         assert_eq!(asm.data[..5], [0x49, 0x0f, 0xb6, 0x45, 0x00]);
         asm.addr = 0;
 
         // 4c 0f b6 2a             movzx  r13,BYTE PTR [rdx]
-        asm.mov_to_reg(X64Register::R13, X64Register::RDX);
+        asm.mov_to_reg(X64Register::R13, X64Register::RDX, 0);
         assert_eq!(asm.data[..4], [0x4c, 0x0f, 0xb6, 0x2a]);
         asm.addr = 0;
     }
@@ -766,6 +930,32 @@ mod test {
         assert_eq!(asm.data[..6], [0x0f, 0x85, 0x0b, 0x0b, 0x0a, 0x0a]);
     }
 
+    #[test]
+    fn jc() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        asm.jc(0x0A0A_0B0B);
+        assert_eq!(asm.data[..6], [0x0f, 0x82, 0x0b, 0x0b, 0x0a, 0x0a]);
+    }
+
+    #[test]
+    fn jnc() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        asm.jnc(0x0A0A_0B0B);
+        assert_eq!(asm.data[..6], [0x0f, 0x83, 0x0b, 0x0b, 0x0a, 0x0a]);
+    }
+
+    #[test]
+    fn jmp_label() {
+        let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
+
+        // e9 fb ff ff ff          jmp    <self>
+        asm.label("here".to_owned());
+        asm.jmp_label("here".to_owned());
+        assert_eq!(asm.data[..5], [0xe9, 0xfb, 0xff, 0xff, 0xff]);
+    }
+
     #[test]
     fn call() {
         let mut asm = Assembler { addr: 0, data: &mut [0; 32], labels: HashMap::new() };
@@ -0,0 +1,205 @@
+use crate::ir::{IrCode, IrNode, IrOp};
+
+/// Interval of pointer positions relative to the start of the program, missing ends
+/// are unbounded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Range {
+    pub low: Option<i64>,
+    pub high: Option<i64>,
+}
+
+impl Range {
+    pub fn exact(position: i64) -> Self {
+        Range { low: Some(position), high: Some(position) }
+    }
+
+    fn shift(self, by: i64) -> Self {
+        Range { low: self.low.map(|t| t + by), high: self.high.map(|t| t + by) }
+    }
+
+    /// Smallest range containing both ranges.
+    fn join(self, other: Range) -> Self {
+        Range {
+            low: self.low.and_then(|t| other.low.map(|u| t.min(u))),
+            high: self.high.and_then(|t| other.high.map(|u| t.max(u))),
+        }
+    }
+
+    /// Range `next` which grows past `self` at loop head, ends which moved become
+    /// unbounded so that loops are analysed in a few iterations.
+    fn widen(self, next: Range) -> Self {
+        Range {
+            low: self.low.filter(|_| next.low == self.low),
+            high: self.high.filter(|_| next.high == self.high),
+        }
+    }
+}
+
+/// Pointer bounds computed by `IrCode::pointer_bounds`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Bounds {
+    /* range of pointer before each op, in order of IrCode::iter */
+    pub pointer: Vec<Range>,
+    /* range of cells accessed by ops, `None` when no cell is */
+    pub cells: Option<Range>,
+}
+
+impl Bounds {
+    /// Number of cells the program needs, it provably stays in `0..size`. `None` when
+    /// it may access cells left of the start or arbitrarily far right.
+    pub fn tape_size(&self) -> Option<usize> {
+        match self.cells {
+            None => Some(0),
+            Some(Range { low: Some(low), high: Some(high) }) if low >= 0 => Some(high as usize + 1),
+            Some(_) => None,
+        }
+    }
+}
+
+struct Analysis {
+    bounds: Bounds,
+    /* pointer ranges are recorded once loops reached their fixpoint */
+    record: bool,
+}
+
+impl Analysis {
+    fn access(&mut self, range: Range) {
+        self.bounds.cells = Some(self.bounds.cells.map_or(range, |t| t.join(range)));
+    }
+
+    /// Range of pointer after `block` is executed with pointer in `range`.
+    fn block(&mut self, block: &[IrNode], mut range: Range) -> Range {
+        for node in block {
+            if self.record {
+                self.bounds.pointer.push(range);
+            }
+            range = match &node.op {
+                IrOp::Move(x) => range.shift(i64::from(*x)),
+                IrOp::Add(offset, _) | IrOp::SetIndirect(offset, _) | IrOp::Write(offset)
+                | IrOp::Read(offset) | IrOp::Iterations(offset, _) => {
+                    self.access(range.shift(i64::from(*offset)));
+                    range
+                }
+                IrOp::MulCopy(source, target, _) => {
+                    self.access(range.shift(i64::from(*source)));
+                    self.access(range.shift(i64::from(*target)));
+                    range
+                }
+                IrOp::WriteConst(_) => range,
+                IrOp::ScanRight(_) => {
+                    let range = Range { high: None, ..range };
+                    self.access(range);
+                    range
+                }
+                IrOp::ScanLeft(_) => {
+                    let range = Range { low: None, ..range };
+                    self.access(range);
+                    range
+                }
+                IrOp::IfNonZero(body) => {
+                    self.access(range);
+                    range.join(self.block(body, range))
+                }
+                IrOp::Loop(body) => self.repeat(body, range),
+            };
+        }
+
+        range
+    }
+
+    /// Range of pointer at head of loop with `body` entered with pointer in `range`,
+    /// which is also its range after the loop.
+    fn repeat(&mut self, body: &[IrNode], range: Range) -> Range {
+        let record = std::mem::replace(&mut self.record, false);
+        let mut head = range;
+        loop {
+            self.access(head);
+            let next = head.join(self.block(body, head));
+            if next == head { break; }
+            head = head.widen(next);
+        }
+
+        self.record = record;
+        if record {
+            self.block(body, head);
+        }
+        head
+    }
+}
+
+impl IrCode {
+    /// Ranges of pointer and accessed cells relative to the start of the program. They
+    /// are exact in straight-line code, loops which may move the pointer make it
+    /// unbounded in that direction.
+    pub fn pointer_bounds(&self) -> Bounds {
+        let mut analysis = Analysis { bounds: Bounds { pointer: Vec::new(), cells: None }, record: true };
+        analysis.block(&self.ops, Range::exact(0));
+        analysis.bounds
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bounds::Range;
+    use crate::brainfuck::Program;
+    use crate::generator::Generator;
+    use crate::interpreter::Interpreter;
+    use crate::ir::IrCode;
+    use crate::observer::Profiler;
+
+    fn tape_size(source: &str) -> Option<usize> {
+        let mut ir_code = IrCode::new(&Program::from_string(source));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        ir_code.pointer_bounds().tape_size()
+    }
+
+    #[test]
+    fn tracks_straight_line_code() {
+        let ir_code = IrCode::new(&Program::from_string(">>+<.>>>-"));
+        let bounds = ir_code.pointer_bounds();
+
+        assert_eq!(bounds.pointer.len(), ir_code.len());
+        assert_eq!(bounds.pointer[2], Range::exact(2));
+        assert_eq!(bounds.pointer[8], Range::exact(4));
+        assert_eq!(bounds.cells, Some(Range { low: Some(1), high: Some(4) }));
+        assert_eq!(bounds.tape_size(), Some(5));
+    }
+
+    #[test]
+    fn keeps_balanced_loops_exact() {
+        assert_eq!(tape_size("+[>+>+<<-]>>."), Some(3));
+        assert_eq!(tape_size(",[>,[>+<-]<-]"), Some(3));
+        assert_eq!(tape_size(",[>,.<[-]]"), Some(2));
+        assert_eq!(tape_size(""), Some(0));
+    }
+
+    #[test]
+    fn widens_moving_loops() {
+        let bounds = IrCode::new(&Program::from_string("+[>+]<")).pointer_bounds();
+
+        assert_eq!(bounds.pointer[2], Range { low: Some(0), high: None });
+        assert_eq!(bounds.cells, Some(Range { low: Some(0), high: None }));
+        assert_eq!(tape_size("+[>+]"), None);
+        assert_eq!(tape_size(">>,[<]"), None);
+        assert_eq!(tape_size(",[>,[<]]"), None);
+        assert_eq!(tape_size("<+"), None);
+    }
+
+    #[test]
+    fn bounds_executed_programs() {
+        for seed in 0..200 {
+            let generated = Generator::new(seed).generate();
+            let program = Program::from_string(&generated.source);
+            let size = match IrCode::new(&program).pointer_bounds().tape_size() {
+                Some(t) => t,
+                None => continue,
+            };
+
+            let mut vm = Interpreter::new(&program, generated.input.as_slice(), std::io::sink())
+                .with_observer(Profiler::default());
+            vm.interpret().unwrap();
+            assert!(vm.observer.highest_address < size.max(1), "{}", generated.source);
+        }
+    }
+}
@@ -1,6 +1,99 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
 /// Maximum memory in bytes an interpreter can use.
 pub const MAX_MEMORY: usize = 30000;
 
+/// Behaviour of cell arithmetic when value goes over 255 or under 0.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    Saturate,
+    Trap,
+}
+
+impl Overflow {
+    /// Adds `b` to cell value `a`. Returns `None` if the addition traps.
+    #[inline]
+    pub fn add(self, a: u8, b: u8) -> Option<u8> {
+        match self {
+            Overflow::Wrap => Some(a.wrapping_add(b)),
+            Overflow::Saturate => Some(a.saturating_add(b)),
+            Overflow::Trap => a.checked_add(b),
+        }
+    }
+
+    /// Subtracts `b` from cell value `a`. Returns `None` if the subtraction traps.
+    #[inline]
+    pub fn sub(self, a: u8, b: u8) -> Option<u8> {
+        match self {
+            Overflow::Wrap => Some(a.wrapping_sub(b)),
+            Overflow::Saturate => Some(a.saturating_sub(b)),
+            Overflow::Trap => a.checked_sub(b),
+        }
+    }
+
+    /// Adds `delta` to cell value `a`, subtracts when it is negative. Returns `None` if
+    /// the change traps.
+    #[inline]
+    pub fn add_signed(self, a: u8, delta: i16) -> Option<u8> {
+        let value = i32::from(a) + i32::from(delta);
+        match self {
+            Overflow::Wrap => Some(value.rem_euclid(256) as u8),
+            Overflow::Saturate => Some(value.clamp(0, 255) as u8),
+            Overflow::Trap => if (0..=255).contains(&value) { Some(value as u8) } else { None },
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(Overflow::Wrap),
+            "saturate" => Ok(Overflow::Saturate),
+            "trap" => Ok(Overflow::Trap),
+            _ => Err(format!("unknown overflow mode: {}", s)),
+        }
+    }
+}
+
+/// Position of instruction in source file (both numbers starting at 1).
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Error which stops execution of brainfuck program.
+///
+/// Both the interpreter and the JIT report the instruction which overflowed, also inside
+/// a fused run of `+` or `-`. Adds made of other instructions (like unrolled loops) report
+/// the first instruction of their IR op.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RuntimeError {
+    Overflow(Location),
+    Underflow(Location),
+    InfiniteLoop(Location),
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            RuntimeError::Overflow(l) => write!(f, "cell overflow at {}", l),
+            RuntimeError::Underflow(l) => write!(f, "cell underflow at {}", l),
+            RuntimeError::InfiniteLoop(l) => write!(f, "infinite loop detected at {}", l),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Op {
     IncrementPtr,
@@ -29,18 +122,41 @@ impl Op {
     }
 }
 
+/// Loop whose body contains no nested loops, does no I/O and moves the pointer
+/// back to the loop cell at the end of every iteration.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BalancedLoop {
+    /* range of cells touched by the body relative to the loop cell */
+    pub min_offset: isize,
+    pub max_offset: isize,
+    /* whether the body modifies the loop cell and by how much (mod 256) per iteration */
+    pub changes_condition: bool,
+    pub step: u8,
+    /* whether the body modifies any cell, which may trap */
+    pub changes_cells: bool,
+}
+
 pub struct Program {
     pub instructions: Vec<Op>,
+    /* source location of each instruction */
+    pub locations: Vec<Location>,
 }
 
 impl Program {
     pub fn from_string(string: &str) -> Self {
-        let ops: Vec<Op> = string.chars()
-            .map(|c| -> Option<Op> { Op::from_char(c) })
-            .filter_map(|x| x)
-            .collect();
+        let mut ops: Vec<Op> = Vec::new();
+        let mut locations: Vec<Location> = Vec::new();
+
+        for (line_idx, line) in string.lines().enumerate() {
+            for (column_idx, c) in line.chars().enumerate() {
+                if let Some(op) = Op::from_char(c) {
+                    ops.push(op);
+                    locations.push(Location { line: line_idx + 1, column: column_idx + 1 });
+                }
+            }
+        }
 
-        Program { instructions: ops }
+        Program { instructions: ops, locations }
     }
 
     pub fn find_matching_jump_end(&self, jump_start_pos: usize) -> usize {
@@ -76,12 +192,60 @@ impl Program {
             pos -= 1
         }
     }
+
+    /// Returns how far the pointer moves in each iteration of loop starting at
+    /// `jump_start_pos` if the loop only searches for zero cell (like `[>]` or `[<<]`).
+    pub fn scan_loop(&self, jump_start_pos: usize) -> Option<isize> {
+        let mut stride = 0;
+
+        for op in self.instructions[jump_start_pos + 1..].iter() {
+            match op {
+                Op::IncrementPtr => stride += 1,
+                Op::DecrementPtr => stride -= 1,
+                Op::JumpBackward if stride != 0 => return Some(stride),
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    /// Returns effect of loop starting at `jump_start_pos` if it is a `BalancedLoop`.
+    pub fn balanced_loop(&self, jump_start_pos: usize) -> Option<BalancedLoop> {
+        let mut offset: isize = 0;
+        let mut body = BalancedLoop { min_offset: 0, max_offset: 0, changes_condition: false, step: 0, changes_cells: false };
+
+        for op in self.instructions[jump_start_pos + 1..].iter() {
+            match op {
+                Op::IncrementPtr => offset += 1,
+                Op::DecrementPtr => offset -= 1,
+                Op::IncrementMemory if offset == 0 => {
+                    body.changes_condition = true;
+                    body.changes_cells = true;
+                    body.step = body.step.wrapping_add(1);
+                }
+                Op::DecrementMemory if offset == 0 => {
+                    body.changes_condition = true;
+                    body.changes_cells = true;
+                    body.step = body.step.wrapping_sub(1);
+                }
+                Op::IncrementMemory | Op::DecrementMemory => body.changes_cells = true,
+                Op::ReadByte | Op::WriteByte | Op::JumpForward => return None,
+                Op::JumpBackward if offset == 0 => return Some(body),
+                Op::JumpBackward => return None,
+            }
+            body.min_offset = body.min_offset.min(offset);
+            body.max_offset = body.max_offset.max(offset);
+        }
+
+        None
+    }
 }
 
 
 #[cfg(test)]
 mod test {
-    use crate::brainfuck::{Program, Op};
+    use crate::brainfuck::{Program, Op, Location, Overflow, BalancedLoop};
 
     #[test]
     fn check_supported_ops() {
@@ -108,6 +272,33 @@ mod test {
         assert_eq!(program.instructions[3], Op::JumpBackward);
     }
 
+    #[test]
+    fn records_locations() {
+        let program = Program::from_string("+ comment +\n\n  -");
+
+        assert_eq!(program.locations.len(), 3);
+        assert_eq!(program.locations[0], Location { line: 1, column: 1 });
+        assert_eq!(program.locations[1], Location { line: 1, column: 11 });
+        assert_eq!(program.locations[2], Location { line: 3, column: 3 });
+        assert_eq!(program.locations[2].to_string(), "3:3");
+    }
+
+    #[test]
+    fn overflow_modes() {
+        assert_eq!(Overflow::Wrap.add(255, 2), Some(1));
+        assert_eq!(Overflow::Wrap.sub(0, 1), Some(255));
+        assert_eq!(Overflow::Saturate.add(250, 10), Some(255));
+        assert_eq!(Overflow::Saturate.sub(3, 10), Some(0));
+        assert_eq!(Overflow::Trap.add(250, 10), None);
+        assert_eq!(Overflow::Trap.sub(3, 10), None);
+        assert_eq!(Overflow::Trap.add(250, 5), Some(255));
+        assert_eq!(Overflow::Wrap.add_signed(1, -2), Some(255));
+        assert_eq!(Overflow::Saturate.add_signed(250, 256), Some(255));
+        assert_eq!(Overflow::Trap.add_signed(3, -3), Some(0));
+        assert_eq!(Overflow::Trap.add_signed(3, -4), None);
+        assert_eq!("saturate".parse::<Overflow>(), Ok(Overflow::Saturate));
+    }
+
     #[test]
     fn find_matching_parentheses() {
         let program = Program::from_string("[[][]]");
@@ -118,4 +309,28 @@ mod test {
         assert_eq!(program.find_matching_jump_end(3), 4);
         assert_eq!(program.find_matching_jump_start(4), 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn finds_scan_loops() {
+        let program = Program::from_string("[>][<<][><][>+][>><<<]");
+
+        assert_eq!(program.scan_loop(0), Some(1));
+        assert_eq!(program.scan_loop(3), Some(-2));
+        assert_eq!(program.scan_loop(7), None);
+        assert_eq!(program.scan_loop(11), None);
+        assert_eq!(program.scan_loop(15), Some(-1));
+    }
+
+    #[test]
+    fn finds_balanced_loops() {
+        let program = Program::from_string("[<>][->>+<<][>+<][>][[-]][,][.]");
+
+        assert_eq!(program.balanced_loop(0), Some(BalancedLoop { min_offset: -1, max_offset: 0, changes_condition: false, step: 0, changes_cells: false }));
+        assert_eq!(program.balanced_loop(4), Some(BalancedLoop { min_offset: 0, max_offset: 2, changes_condition: true, step: 255, changes_cells: true }));
+        assert_eq!(program.balanced_loop(12), Some(BalancedLoop { min_offset: 0, max_offset: 1, changes_condition: false, step: 0, changes_cells: true }));
+        assert_eq!(program.balanced_loop(17), None);
+        assert_eq!(program.balanced_loop(20), None);
+        assert_eq!(program.balanced_loop(25), None);
+        assert_eq!(program.balanced_loop(28), None);
+    }
+}
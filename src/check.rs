@@ -0,0 +1,149 @@
+use std::fmt::{Display, Formatter};
+
+use crate::brainfuck::{Location, Overflow, MAX_MEMORY};
+use crate::ir::{IrCode, IrNode, IrOp, Optimizations, Origin};
+
+/// Op breaking an invariant of IrCode.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InvalidIr {
+    pub location: Location,
+    /* optimizations which created the op */
+    pub optimizations: Optimizations,
+    pub message: String,
+}
+
+impl Display for InvalidIr {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)?;
+        if !self.optimizations.is_empty() {
+            write!(f, " (op rewritten by {})", self.optimizations.names().join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid(node: &IrNode, message: String) -> Result<(), InvalidIr> {
+    Err(InvalidIr { location: node.origin.location, optimizations: node.origin.optimizations, message })
+}
+
+/// Checks ops of `block` nested in a loop with `parent` origin.
+fn check_block(block: &[IrNode], overflow: Overflow, parent: Option<Origin>) -> Result<(), InvalidIr> {
+    for node in block {
+        let origin = node.origin;
+        if origin.first > origin.last {
+            return invalid(node, format!("origin starts at instruction {} after it ends at {}", origin.first, origin.last));
+        }
+        if let Some(parent) = parent.filter(|t| origin.first < t.first || origin.last > t.last) {
+            return invalid(node, format!("origin {}-{} is outside of its loop {}-{}", origin.first, origin.last, parent.first, parent.last));
+        }
+
+        match &node.op {
+            IrOp::Add(offset, _) | IrOp::SetIndirect(offset, _) | IrOp::Write(offset)
+            | IrOp::Read(offset) | IrOp::Iterations(offset, _) | IrOp::MulCopy(offset, _, _) | IrOp::MulCopy(_, offset, _)
+            if offset.unsigned_abs() as usize >= MAX_MEMORY => {
+                return invalid(node, format!("offset {} is outside of memory", offset));
+            }
+            /* MulCopy and Iterations always wrap */
+            IrOp::MulCopy(..) | IrOp::Iterations(..) if overflow != Overflow::Wrap => {
+                return invalid(node, format!("{:?} needs wrapping cells, but cells {}", node.op, describe(overflow)));
+            }
+            IrOp::Iterations(_, 0) => return invalid(node, "iterations with zero step".to_owned()),
+            IrOp::ScanRight(0) | IrOp::ScanLeft(0) => return invalid(node, "scan with zero stride".to_owned()),
+            IrOp::WriteConst(bytes) if bytes.is_empty() => return invalid(node, "constant write of no bytes".to_owned()),
+            IrOp::Loop(body) | IrOp::IfNonZero(body) => check_block(body, overflow, Some(origin))?,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn describe(overflow: Overflow) -> &'static str {
+    match overflow {
+        Overflow::Wrap => "wrap",
+        Overflow::Saturate => "saturate",
+        Overflow::Trap => "trap",
+    }
+}
+
+impl IrCode {
+    /// Checks invariants which optimizations and compiler rely on: ops have valid
+    /// arguments for the overflow mode, address cells inside of memory and origins of
+    /// loop bodies are nested in origin of their loop.
+    pub fn verify(&self) -> Result<(), InvalidIr> {
+        self.verify_block(&self.ops)
+    }
+
+    /// Checks invariants of ops in `block` (which need not be part of the program).
+    pub fn verify_block(&self, block: &[IrNode]) -> Result<(), InvalidIr> {
+        check_block(block, self.overflow, None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Program, Overflow};
+    use crate::ir::{IrCode, IrOp, Optimizations};
+
+    fn error(text: &str, overflow: Overflow) -> String {
+        let mut ir_code = IrCode::parse(text).unwrap();
+        ir_code.overflow = overflow;
+        ir_code.verify().err().unwrap().to_string()
+    }
+
+    #[test]
+    fn accepts_optimized_programs() {
+        for file in &["hello_world.bf", "pi.bf", "sierpinski.bf", "squares.bf", "hanoi.bf"] {
+            let path = format!("{}/sample_programs/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let program = Program::from_string(&std::fs::read_to_string(path).unwrap());
+            let mut ir_code = IrCode::new(&program);
+            assert_eq!(ir_code.verify(), Ok(()));
+
+            ir_code.verify_passes = true;
+            ir_code.optimize();
+            assert_eq!(ir_code.verify(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_ops() {
+        assert_eq!(error("add 1\nloop {\n  iterations 0\n}", Overflow::Wrap), "3:3: iterations with zero step");
+        assert_eq!(error("mulcopy +1, *2", Overflow::Trap), "1:1: MulCopy(0, 1, 2) needs wrapping cells, but cells trap");
+        assert_eq!(error("if { scanleft 0 }", Overflow::Wrap), "1:6: scan with zero stride");
+        assert_eq!(error("write \"\"", Overflow::Wrap), "1:1: constant write of no bytes");
+        assert_eq!(error("set -30000, 1", Overflow::Wrap), "1:1: offset -30000 is outside of memory");
+        assert_eq!(error("mulcopy +1, +40000, *1", Overflow::Wrap), "1:1: offset 40000 is outside of memory");
+    }
+
+    #[test]
+    fn rejects_body_outside_of_loop() {
+        let mut ir_code = IrCode::new(&Program::from_string("+[-.]"));
+        ir_code.ops[1].origin.last = 2;
+
+        assert_eq!(ir_code.verify().err().unwrap().to_string(), "1:4: origin 3-3 is outside of its loop 1-2");
+
+        if let IrOp::Loop(body) = &mut ir_code.ops[1].op {
+            body[1].origin.optimizations = Optimizations::CONSECUTIVE;
+        }
+        assert_eq!(ir_code.verify().err().unwrap().to_string(), "1:4: origin 3-3 is outside of its loop 1-2 (op rewritten by consecutive)");
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid IR before optimization: 1:1: iterations with zero step")]
+    fn optimization_checks_ir() {
+        let mut ir_code = IrCode::parse("iterations 0").unwrap();
+        ir_code.verify_passes = true;
+        ir_code.passes = Optimizations::empty();
+        ir_code.optimize();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid IR after offset-addressing: 1:30001: offset 30000 is outside of memory")]
+    fn optimization_names_pass_which_broke_ir() {
+        /* offset addressing moves the add to a cell outside of memory */
+        let source = format!("{}+{}", ">".repeat(30000), "<".repeat(30000));
+        let mut ir_code = IrCode::new(&Program::from_string(&source));
+        ir_code.verify_passes = true;
+        ir_code.optimize();
+    }
+}
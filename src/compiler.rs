@@ -1,12 +1,19 @@
-use std::io::{stdin, Read};
+use std::io::{stdin, stdout, Read, Write, Cursor};
+use std::cell::RefCell;
 use memmap::MmapMut;
-use crate::ir::{IrCode, IrOp};
-use crate::brainfuck::MAX_MEMORY;
+use crate::ir::{IrCode, IrOp, IrNode, inverse};
+use crate::brainfuck::{MAX_MEMORY, Overflow, RuntimeError};
 use crate::assembler::{Assembler, X64Register};
 
 /* Brainfuck Read and Write procedures. */
 extern "win64" fn putchar(character: u8) {
-    print!("{}", character as char);
+    /* raw byte like constant output, `char` would be encoded as UTF-8 */
+    stdout().write_all(&[character]).expect("cannot write to stdout");
+}
+
+extern "win64" fn write(bytes: *const u8, length: u64) {
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, length as usize) };
+    stdout().write_all(bytes).expect("cannot write to stdout");
 }
 
 extern "win64" fn getchar() -> u8 {
@@ -15,87 +22,102 @@ extern "win64" fn getchar() -> u8 {
     buff[0]
 }
 
+thread_local! {
+    static BUFFERED_INPUT: RefCell<Cursor<Vec<u8>>> = const { RefCell::new(Cursor::new(Vec::new())) };
+    static BUFFERED_OUTPUT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+extern "win64" fn buffered_putchar(character: u8) {
+    BUFFERED_OUTPUT.with(|t| t.borrow_mut().push(character));
+}
+
+extern "win64" fn buffered_write(bytes: *const u8, length: u64) {
+    let bytes = unsafe { std::slice::from_raw_parts(bytes, length as usize) };
+    BUFFERED_OUTPUT.with(|t| t.borrow_mut().extend_from_slice(bytes));
+}
+
+/* we cannot panic here, so reading past the end of input returns 0 */
+extern "win64" fn buffered_getchar() -> u8 {
+    let mut buff: [u8; 1] = [0; 1];
+    BUFFERED_INPUT.with(|t| t.borrow_mut().read_exact(&mut buff).unwrap_or(()));
+    buff[0]
+}
+
 const PUTCHAR_REGISTER: X64Register = X64Register::R12;
 const GETCHAR_REGISTER: X64Register = X64Register::R13;
 const PTR_REGISTER: X64Register = X64Register::R14;
+const WRITE_REGISTER: X64Register = X64Register::R15;
 
 pub struct IoFn {
     putchar_ptr: usize,
     getchar_ptr: usize,
+    /* writes bytes of constant output: fn(pointer, length) */
+    write_ptr: usize,
 }
 
 impl IoFn {
     pub fn std() -> Self {
-        return IoFn {
-            putchar_ptr: putchar as usize,
-            getchar_ptr: getchar as usize,
-        };
+        IoFn {
+            putchar_ptr: putchar as *const () as usize,
+            getchar_ptr: getchar as *const () as usize,
+            write_ptr: write as *const () as usize,
+        }
     }
+
+    /// Reads input from and writes output to buffers of current thread. Input is set
+    /// by `set_buffered_input` and output collected by `take_buffered_output`.
+    pub fn buffered() -> Self {
+        IoFn {
+            putchar_ptr: buffered_putchar as *const () as usize,
+            getchar_ptr: buffered_getchar as *const () as usize,
+            write_ptr: buffered_write as *const () as usize,
+        }
+    }
+}
+
+/// Sets input read by programs compiled with `IoFn::buffered` on this thread.
+pub fn set_buffered_input(input: Vec<u8>) {
+    BUFFERED_INPUT.with(|t| *t.borrow_mut() = Cursor::new(input));
+}
+
+/// Returns and clears output written by programs compiled with `IoFn::buffered` on this thread.
+pub fn take_buffered_output() -> Vec<u8> {
+    BUFFERED_OUTPUT.with(|t| std::mem::take(&mut *t.borrow_mut()))
 }
 
 impl IrCode {
     pub fn compile(&mut self, io_fn: IoFn) -> Brainfuck {
-        let length = self.len();
-
-        let mut brainfuck = Brainfuck::new(256 + length * 16);
+        let code_bytes: usize = self.iter().map(|t| op_size(&t.op, self.overflow)).sum();
+        /* constant output is stored after the code */
+        let const_bytes: usize = self.iter().map(|t| match &t.op {
+            IrOp::WriteConst(bytes) => bytes.len(),
+            _ => 0,
+        }).sum();
+
+        let mut brainfuck = Brainfuck::new(256 + code_bytes + const_bytes);
+        let base = brainfuck.program.as_ptr() as u64;
         let mut assembler: Assembler = Assembler::new(&mut brainfuck.program);
+        let mut constants = Vec::new();
 
         assembler.push(X64Register::RBX);
         assembler.push(PUTCHAR_REGISTER);
         assembler.push(GETCHAR_REGISTER);
         assembler.push(PTR_REGISTER);
-        assembler.sub(X64Register::RSP, 168);
+        assembler.push(WRITE_REGISTER);
+        assembler.sub(X64Register::RSP, 160);
 
         assembler.mov(PUTCHAR_REGISTER, io_fn.putchar_ptr as u64);
         assembler.mov(GETCHAR_REGISTER, io_fn.getchar_ptr as u64);
-        assembler.mov(PTR_REGISTER, brainfuck.memory.as_ptr() as u64);
-
-        let mut parentheses_depth = 0usize;
-        let mut parentheses_id_stack = [0; 4096];
-
-        /* 1. generate instructions */
-        for op in self.iter() {
-            match op {
-                IrOp::Noop(_) => {}
-                IrOp::Right(_, data) => assembler.add(PTR_REGISTER, (*data).into()),
-                IrOp::Left(_, data) => assembler.sub(PTR_REGISTER, (*data).into()),
-                IrOp::Add(_, data) => assembler.add_indirect(PTR_REGISTER, *data),
-                IrOp::Sub(_, data) => assembler.sub_indirect(PTR_REGISTER, *data),
-                IrOp::SetIndirect(_, data) => assembler.mov_indirect(PTR_REGISTER, *data),
-                IrOp::MulCopy(_, offset, factor) => {
-                    assembler.mov_to_reg(X64Register::RAX, PTR_REGISTER);
-                    assembler.mov(X64Register::RBX, *factor as u64);
-                    if *factor != 1 {
-                        assembler.mul_signed(X64Register::RBX);
-                    }
-                    assembler.add_to_mem_offset(PTR_REGISTER, X64Register::RAX, *offset)
-                }
-                IrOp::Write(_) => {
-                    assembler.mov_to_reg(X64Register::RCX, PTR_REGISTER);
-                    assembler.call(PUTCHAR_REGISTER);
-                }
-                IrOp::Read(_) => {
-                    assembler.call(GETCHAR_REGISTER);
-                    assembler.mov_to_memory(PTR_REGISTER, X64Register::RAX);
-                }
-                IrOp::JumpIfZero(_, _) => {
-                    parentheses_depth += 1;
-                    parentheses_id_stack[parentheses_depth] += 1;
+        assembler.mov(WRITE_REGISTER, io_fn.write_ptr as u64);
+        assembler.mov_reg(PTR_REGISTER, X64Register::RCX); /* memory is the first argument */
 
-                    assembler.label(format!("[{}_{}", parentheses_depth, parentheses_id_stack[parentheses_depth]));
-                    assembler.cmp_indirect(PTR_REGISTER, 0);
-                    assembler.je(0x00AA_BBCC);
-                }
-                IrOp::JumpIfNotZero(_, _) => {
-                    assembler.cmp_indirect(PTR_REGISTER, 0);
-                    assembler.jne_label(format!("[{}_{}", parentheses_depth, parentheses_id_stack[parentheses_depth]));
-                    assembler.label(format!("]{}_{}", parentheses_depth, parentheses_id_stack[parentheses_depth]));
-                    parentheses_depth -= 1;
-                }
-            }
-        }
+        compile_block(&mut assembler, self, &self.ops, &mut brainfuck.traps, &mut constants);
 
-        assembler.add(X64Register::RSP, 168);
+        /* return 0 when program finished or (index of trap + 1) when it trapped */
+        assembler.mov(X64Register::RAX, 0);
+        assembler.label("exit".to_owned());
+        assembler.add(X64Register::RSP, 160);
+        assembler.pop(WRITE_REGISTER);
         assembler.pop(PTR_REGISTER);
         assembler.pop(GETCHAR_REGISTER);
         assembler.pop(PUTCHAR_REGISTER);
@@ -103,30 +125,233 @@ impl IrCode {
 
         assembler.ret();
 
+        /* stubs of counter loop traps, adds compute the trap number themselves */
+        for trap in 1..=brainfuck.traps.len() {
+            if assembler.labels.contains_key(&format!("jne{}", trap)) {
+                assembler.label(format!("trap{}", trap));
+                assembler.mov(X64Register::RAX, trap as u64);
+                assembler.jmp_label("exit".to_owned());
+            }
+        }
+
+        /* place constant output and patch its addresses into the code */
+        for (addr, bytes) in constants {
+            let data = assembler.addr;
+            assembler.bytes(bytes);
+            assembler.addr = addr;
+            assembler.mov(X64Register::RCX, base + data as u64);
+            assembler.addr = data + bytes.len();
+        }
+
         /* save actual program length */
         brainfuck.length = assembler.addr;
+        /* program which provably stays in a few cells does not need the whole tape */
+        if let Some(size) = self.pointer_bounds().tape_size() {
+            brainfuck.tape_size = size.clamp(1, MAX_MEMORY);
+        }
 
-        /* 2. resolve jumps */
-        let jumps_to_fix: Vec<(String, usize)> = assembler.labels
-            .iter()
-            .filter(|(k, _)| k.starts_with('['))
-            .map(|(k, v)| (k.clone(), *v))
-            .collect();
-
-        for (k, v) in jumps_to_fix {
-            assembler.addr = v;
-            assembler.cmp_indirect(PTR_REGISTER, 0);
-            assembler.je_label(k.replace('[', "]"));
+        /* resolve jumps to traps */
+        for trap in 1..=brainfuck.traps.len() {
+            if let Some(addr) = assembler.labels.get(&format!("jne{}", trap)) {
+                assembler.addr = *addr;
+                assembler.jne_label(format!("trap{}", trap));
+            } else if let Some(addr) = assembler.labels.get(&format!("jmp{}", trap)) {
+                assembler.addr = *addr;
+                assembler.jmp_label("exit".to_owned());
+            }
         }
 
         brainfuck
     }
 }
 
+/// Largest number of bytes emitted for `op` (without ops of its body), including
+/// trap stubs placed after the code.
+fn op_size(op: &IrOp, overflow: Overflow) -> usize {
+    /* mov rax, imm64 and jmp to exit */
+    const STUB: usize = 15;
+    match op {
+        IrOp::Add(_, x) => {
+            /* add with disp32 per chunk of at most 255, then jnc over mov (trapping
+             * cells also compute the trap number and jump to exit) */
+            let check = match overflow {
+                Overflow::Wrap => 0,
+                Overflow::Saturate => 14,
+                Overflow::Trap => 37,
+            };
+            (x.unsigned_abs() as usize).div_ceil(255) * (8 + check)
+        }
+        /* with disp32 offsets, a test and jne before even steps */
+        IrOp::Iterations(_, step) if step % 2 == 0 => 52 + STUB,
+        IrOp::Iterations(..) => 38,
+        /* mulcopy with two disp32 offsets is the longest of other ops */
+        _ => 32,
+    }
+}
+
+/// Emits machine code for ops of `block`, loops are compiled recursively. Constant
+/// output is collected in `constants` with address of the instruction loading it.
+fn compile_block<'a>(assembler: &mut Assembler, ir_code: &IrCode, block: &'a [IrNode], traps: &mut Vec<RuntimeError>, constants: &mut Vec<(usize, &'a [u8])>) {
+    let overflow = ir_code.overflow;
+    for node in block {
+        match &node.op {
+            IrOp::Move(data) if *data >= 0 => assembler.add(PTR_REGISTER, *data as u32),
+            IrOp::Move(data) => assembler.sub(PTR_REGISTER, data.unsigned_abs()),
+            IrOp::Add(offset, data) => {
+                /* trapping add has a trap for each instruction it was folded from */
+                let base = traps.len();
+                if overflow == Overflow::Trap {
+                    let error = if *data > 0 { RuntimeError::Overflow } else { RuntimeError::Underflow };
+                    let locations = ir_code.run_locations(node).unwrap_or_else(|| vec![node.origin.location; data.unsigned_abs() as usize]);
+                    traps.extend(locations.into_iter().map(error));
+                }
+
+                /* only a trapping add of 256 does not fit in one byte */
+                let mut added = 0;
+                while added < data.unsigned_abs() {
+                    let chunk = (data.unsigned_abs() - added).min(255) as u8;
+                    added += u16::from(chunk);
+                    if *data > 0 {
+                        assembler.add_indirect(PTR_REGISTER, *offset, chunk);
+                    } else {
+                        assembler.sub_indirect(PTR_REGISTER, *offset, chunk);
+                    }
+                    check_carry(assembler, overflow, *offset, i32::from(added) * i32::from(data.signum()), base);
+                }
+            }
+            IrOp::SetIndirect(offset, data) => assembler.mov_indirect(PTR_REGISTER, *offset, *data),
+            IrOp::MulCopy(source, target, factor) => {
+                assembler.mov_to_reg(X64Register::RAX, PTR_REGISTER, *source);
+                assembler.mov(X64Register::RBX, *factor as u64);
+                if *factor != 1 {
+                    assembler.mul_signed(X64Register::RBX);
+                }
+                assembler.add_to_mem_offset(PTR_REGISTER, X64Register::RAX, *target)
+            }
+            IrOp::Iterations(offset, step) => {
+                /* step = 2^shift * odd, cell must be divisible by 2^shift to ever reach zero */
+                let shift = step.trailing_zeros() as u8;
+                if shift > 0 {
+                    assembler.test_indirect(PTR_REGISTER, *offset, (1 << shift) - 1);
+                    traps.push(RuntimeError::InfiniteLoop(node.origin.location));
+                    assembler.label(format!("jne{}", traps.len()));
+                    assembler.jne(0x00AA_BBCC);
+                }
+
+                /* iterations = (cell / 2^shift) * -odd^-1 (mod 2^(8 - shift)) */
+                assembler.mov_to_reg(X64Register::RAX, PTR_REGISTER, *offset);
+                assembler.shr(X64Register::RAX, shift);
+                assembler.mov(X64Register::RBX, u64::from(inverse(step >> shift).wrapping_neg()));
+                assembler.mul_signed(X64Register::RBX);
+                assembler.and(X64Register::RAX, 0xFF >> shift);
+                assembler.mov_to_mem_offset(PTR_REGISTER, X64Register::RAX, *offset);
+            }
+            IrOp::Write(offset) => {
+                assembler.mov_to_reg(X64Register::RCX, PTR_REGISTER, *offset);
+                assembler.call(PUTCHAR_REGISTER);
+            }
+            IrOp::WriteConst(bytes) => {
+                constants.push((assembler.addr, bytes));
+                assembler.mov(X64Register::RCX, 0);
+                assembler.mov(X64Register::RDX, bytes.len() as u64);
+                assembler.call(WRITE_REGISTER);
+            }
+            IrOp::Read(offset) => {
+                assembler.call(GETCHAR_REGISTER);
+                assembler.mov_to_mem_offset(PTR_REGISTER, X64Register::RAX, *offset);
+            }
+            IrOp::Loop(body) => {
+                assembler.cmp_indirect(PTR_REGISTER, 0, 0);
+                let jump = assembler.addr;
+                assembler.je(0x00AA_BBCC);
+
+                /* condition is already known to be non-zero when jumping back */
+                let begin = assembler.addr;
+                compile_block(assembler, ir_code, body, traps, constants);
+                assembler.cmp_indirect(PTR_REGISTER, 0, 0);
+                assembler.jne(begin as i32 - (assembler.addr as i32 + 6));
+
+                let end = assembler.addr;
+                assembler.addr = jump;
+                assembler.je((end - (jump + 6)) as i32);
+                assembler.addr = end;
+            }
+            IrOp::IfNonZero(body) => {
+                assembler.cmp_indirect(PTR_REGISTER, 0, 0);
+                let jump = assembler.addr;
+                assembler.je(0x00AA_BBCC);
+
+                compile_block(assembler, ir_code, body, traps, constants);
+
+                let end = assembler.addr;
+                assembler.addr = jump;
+                assembler.je((end - (jump + 6)) as i32);
+                assembler.addr = end;
+            }
+            IrOp::ScanRight(stride) => {
+                /* step back first, so the loop also tests the current cell */
+                assembler.sub(PTR_REGISTER, (*stride).into());
+                let begin = assembler.addr;
+                assembler.add(PTR_REGISTER, (*stride).into());
+                assembler.cmp_indirect(PTR_REGISTER, 0, 0);
+                assembler.jne(begin as i32 - (assembler.addr as i32 + 6));
+            }
+            IrOp::ScanLeft(stride) => {
+                assembler.add(PTR_REGISTER, (*stride).into());
+                let begin = assembler.addr;
+                assembler.sub(PTR_REGISTER, (*stride).into());
+                assembler.cmp_indirect(PTR_REGISTER, 0, 0);
+                assembler.jne(begin as i32 - (assembler.addr as i32 + 6));
+            }
+        }
+    }
+}
+
+/// Handles carry flag of preceding add or sub of cell at `offset` as specified by overflow
+/// mode, `added` is the change of the cell so far. Saturating mode stores the boundary to
+/// the cell, trapping mode also stores it (like interpreter, which stops there) and exits
+/// with the trap of the instruction which overflowed, traps of the add follow `base`.
+fn check_carry(assembler: &mut Assembler, overflow: Overflow, offset: i32, added: i32, base: usize) {
+    if overflow == Overflow::Wrap { return; }
+
+    let count = added.unsigned_abs() as usize;
+    let jump = assembler.addr;
+    assembler.jnc(0x00AA_BBCC);
+    if overflow == Overflow::Trap {
+        /* when the k-th instruction overflowed, cell was left at count - k after adds
+         * and at k - 1 - count (mod 256) after subs, trap number is base + k */
+        assembler.mov_to_reg(X64Register::RAX, PTR_REGISTER, offset);
+        let trap = if added > 0 {
+            assembler.neg(X64Register::RAX);
+            (base + count) as i64
+        } else {
+            (base + count) as i64 - 255
+        };
+        if trap >= 0 {
+            assembler.add(X64Register::RAX, trap as u32);
+        } else {
+            assembler.sub(X64Register::RAX, trap.unsigned_abs() as u32);
+        }
+    }
+    assembler.mov_indirect(PTR_REGISTER, offset, if added > 0 { 255 } else { 0 });
+    if overflow == Overflow::Trap {
+        assembler.label(format!("jmp{}", base + count));
+        assembler.jmp(0x00AA_BBCC);
+    }
+
+    let end = assembler.addr;
+    assembler.addr = jump;
+    assembler.jnc((end - (jump + 6)) as i32);
+    assembler.addr = end;
+}
+
 pub struct Brainfuck {
     pub program: MmapMut,
     pub length: usize,
-    memory: [u8; MAX_MEMORY],
+    /* cells the program needs, MAX_MEMORY unless its bounds are known */
+    pub tape_size: usize,
+    /* errors reported by trapping instructions */
+    traps: Vec<RuntimeError>,
 }
 
 impl Brainfuck {
@@ -139,36 +364,60 @@ impl Brainfuck {
         Brainfuck {
             program: binary,
             length: 0,
-            memory: [0; MAX_MEMORY],
+            tape_size: MAX_MEMORY,
+            traps: Vec::new(),
         }
     }
 
-    pub extern "C" fn execute(self) {
+    /// Executes the program with a zeroed tape of `tape_size` cells.
+    pub fn execute(self) -> Result<(), RuntimeError> {
+        let mut memory = vec![0; self.tape_size];
+        self.execute_with_memory(&mut memory)
+    }
+
+    /// Executes the program using provided `memory` as its tape.
+    pub fn execute_with_memory(self, memory: &mut [u8]) -> Result<(), RuntimeError> {
+        assert!(memory.len() >= self.tape_size, "memory is too small");
+
         let executable = self.program.make_exec().expect("cannot make memory executable");
         let ptr = executable.as_ptr() as *const ();
-        let compiled_brainfuck: extern "C" fn() = unsafe { std::mem::transmute(ptr) };
+        let compiled_brainfuck: extern "win64" fn(*mut u8) -> u64 = unsafe { std::mem::transmute(ptr) };
 
-        compiled_brainfuck();
+        match compiled_brainfuck(memory.as_mut_ptr()) {
+            0 => Ok(()),
+            trap => Err(self.traps[trap as usize - 1]),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::ir::{IrCode, IrOp};
-    use crate::brainfuck::Program;
-    use crate::compiler::{IoFn, getchar};
+    use crate::ir::{IrCode, IrOp, IrNode, Origin, Optimizations};
+    use crate::brainfuck::{Program, Overflow, RuntimeError, Location, MAX_MEMORY};
+    use crate::compiler::{IoFn, getchar, set_buffered_input, take_buffered_output};
+    use crate::verify::verify;
+
+    fn ir_code(ops: Vec<IrOp>) -> IrCode {
+        let mut ir_code = IrCode::from_ops(ops.into_iter().map(|op| IrNode { op, origin: Origin::default() }).collect());
+        ir_code.eval_budget = 0;
+        ir_code
+    }
 
     #[test]
     fn does_not_crash() {
-        let mut ir_code = IrCode { ops: vec![IrOp::Noop(None)] };
+        let mut ir_code = ir_code(vec![]);
         let brainfuck = ir_code.compile(IoFn::std());
 
-        brainfuck.execute();
+        brainfuck.execute().unwrap();
     }
 
     static mut OUTPUT: [u8; 4096] = [0; 4096];
     static mut OUTPUT_IDX: usize = 0;
 
+    extern "win64" fn value_write(bytes: *const u8, length: u64) {
+        unsafe { std::slice::from_raw_parts(bytes, length as usize) }.iter().for_each(|t| value_putchar(*t));
+    }
+
     extern "win64" fn value_putchar(character: u8) {
         unsafe {
             OUTPUT[OUTPUT_IDX] = character;
@@ -178,21 +427,217 @@ mod test {
 
     #[test]
     fn copy_multiplied() {
-        let op1 = IrOp::SetIndirect(Some(1), 7);
-        let op2 = IrOp::MulCopy(Some(2), 2, 11);
-        let op3 = IrOp::Right(Some(3), 2);
-        let op4 = IrOp::Write(None);
+        let op1 = IrOp::SetIndirect(0, 7);
+        let op2 = IrOp::MulCopy(0, 2, 11);
+        let op3 = IrOp::Move(2);
+        let op4 = IrOp::Write(0);
 
-        let mut ir_code = IrCode { ops: vec![op1, op2, op3, op4] };
-        let brainfuck = ir_code.compile(IoFn { putchar_ptr: value_putchar as usize, getchar_ptr: getchar as usize });
+        let mut ir_code = ir_code(vec![op1, op2, op3, op4]);
+        let brainfuck = ir_code.compile(IoFn { putchar_ptr: value_putchar as *const () as usize, getchar_ptr: getchar as *const () as usize, write_ptr: value_write as *const () as usize });
 
         unsafe { OUTPUT_IDX = 0; }
 
-        brainfuck.execute();
+        brainfuck.execute().unwrap();
 
         assert_eq!(unsafe { OUTPUT[0] }, b'M');
     }
 
+    static WRITES: std::sync::Mutex<Vec<Vec<u8>>> = std::sync::Mutex::new(Vec::new());
+
+    extern "win64" fn recorded_write(bytes: *const u8, length: u64) {
+        WRITES.lock().unwrap().push(unsafe { std::slice::from_raw_parts(bytes, length as usize) }.to_vec());
+    }
+
+    #[test]
+    fn writes_constants_at_once() {
+        let mut ir_code = IrCode::new(&Program::from_string("++++++++[>+++++++++<-]>.+.<++.>>+++."));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        assert!(ir_code.iter().any(|t| matches!(t.op, IrOp::WriteConst(_))));
+        let brainfuck = ir_code.compile(IoFn { putchar_ptr: value_putchar as *const () as usize, getchar_ptr: getchar as *const () as usize, write_ptr: recorded_write as *const () as usize });
+
+        brainfuck.execute().unwrap();
+
+        assert_eq!(*WRITES.lock().unwrap(), [vec![72, 73, 2, 3]]);
+    }
+
+    #[test]
+    fn scans_for_zero_cells() {
+        for source in &[">+>++>+++<<[>]<.[<]>.", ">+>++>+++>++++<<<[>>]<<.>[<<]>."] {
+            let mut ir_code = IrCode::new(&Program::from_string(source));
+            ir_code.eval_budget = 0;
+            ir_code.optimize();
+            assert!(ir_code.iter().any(|t| matches!(t.op, IrOp::ScanRight(_))));
+            let brainfuck = ir_code.compile(IoFn::buffered());
+
+            brainfuck.execute().unwrap();
+
+            assert_eq!(take_buffered_output(), vec![3, 1]);
+        }
+    }
+
+    #[test]
+    fn copies_to_far_cells() {
+        let op1 = IrOp::Move(255);
+        let op2 = IrOp::Move(255);
+        let op3 = IrOp::SetIndirect(0, 3);
+        let op4 = IrOp::MulCopy(0, -500, 200);
+        let op5 = IrOp::MulCopy(0, 1000, 128);
+        let op6 = IrOp::Write(-500);
+        let op7 = IrOp::Write(1000);
+
+        let mut ir_code = ir_code(vec![op1, op2, op3, op4, op5, op6, op7]);
+        let brainfuck = ir_code.compile(IoFn::buffered());
+        brainfuck.execute().unwrap();
+
+        assert_eq!(take_buffered_output(), vec![(3 * 200 % 256) as u8, (3 * 128 % 256) as u8]);
+    }
+
+    #[test]
+    fn counter_loops_agree_with_interpreter() {
+        /* program and what the input must be divisible by for the loop to finish */
+        for (source, divisor) in &[(",[+>+<]>.", 1), (",[--->++<]>.", 1), (",[-->+++>-<<]>.>.", 2), (",>+<[++++>+<]>.", 4), (",[--------].", 8)] {
+            let program = Program::from_string(source);
+            for input in (0..=255).filter(|t: &u8| t.is_multiple_of(*divisor)) {
+                assert!(verify(&program, &[input], Overflow::Wrap, Optimizations::all()).is_empty(), "{} differs for {}", source, input);
+            }
+        }
+    }
+
+    #[test]
+    fn counter_loops_trap_when_never_finishing() {
+        let mut ir_code = IrCode::new(&Program::from_string(",\n[-->+<]"));
+        ir_code.optimize();
+        set_buffered_input(vec![3]);
+        let brainfuck = ir_code.compile(IoFn::buffered());
+
+        assert_eq!(brainfuck.execute(), Err(RuntimeError::InfiniteLoop(Location { line: 2, column: 1 })));
+    }
+
+    #[test]
+    fn saturates_cells() {
+        let mut ir_code = IrCode::new(&Program::from_string("->+++++++++++++++[<+++++++++++++++++>-]<+.>."));
+        ir_code.overflow = Overflow::Saturate;
+        ir_code.optimize();
+        let brainfuck = ir_code.compile(IoFn { putchar_ptr: value_putchar as *const () as usize, getchar_ptr: getchar as *const () as usize, write_ptr: value_write as *const () as usize });
+
+        unsafe { OUTPUT_IDX = 0; }
+
+        brainfuck.execute().unwrap();
+
+        assert_eq!(unsafe { OUTPUT[0] }, 255);
+        assert_eq!(unsafe { OUTPUT[1] }, 0);
+    }
+
+    #[test]
+    fn traps_on_overflow() {
+        let mut ir_code = IrCode::new(&Program::from_string("+++\n[>+<+]"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.optimize();
+        let brainfuck = ir_code.compile(IoFn::std());
+
+        assert_eq!(brainfuck.execute(), Err(RuntimeError::Overflow(Location { line: 2, column: 5 })));
+    }
+
+    #[test]
+    fn traps_on_underflow() {
+        let mut ir_code = IrCode::new(&Program::from_string("+>+<[->--<]"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.optimize();
+        let brainfuck = ir_code.compile(IoFn::std());
+
+        assert_eq!(brainfuck.execute(), Err(RuntimeError::Underflow(Location { line: 1, column: 9 })));
+    }
+
+    #[test]
+    fn fits_long_runs_of_whole_turns() {
+        /* each add of 256 is split in two, both with their own trap */
+        let ops = (0..2000).flat_map(|_| vec![IrOp::Add(0, 256), IrOp::Add(1, -256), IrOp::Move(2)]).collect();
+        let mut ir_code = ir_code(ops);
+        ir_code.overflow = Overflow::Trap;
+        let brainfuck = ir_code.compile(IoFn::std());
+
+        assert_eq!(brainfuck.execute(), Err(RuntimeError::Overflow(Location::default())));
+    }
+
+    #[test]
+    fn fits_long_runs_of_counter_loops() {
+        let ops = (0..2000).map(|_| IrOp::Iterations(200, 2)).collect();
+        let mut ir_code = ir_code(ops);
+        let brainfuck = ir_code.compile(IoFn::std());
+
+        assert_eq!(brainfuck.execute(), Ok(()));
+    }
+
+    #[test]
+    fn traps_at_instruction_which_overflowed() {
+        /* the JIT adds all three at once, but reports the second '+' like the interpreter */
+        let mut ir_code = IrCode::new(&Program::from_string(",+++"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.optimize();
+        set_buffered_input(vec![254]);
+        let brainfuck = ir_code.compile(IoFn::buffered());
+
+        assert_eq!(brainfuck.execute(), Err(RuntimeError::Overflow(Location { line: 1, column: 3 })));
+
+        /* run longer than 256 is clamped and split in two adds */
+        for (input, column) in [(1, 256), (0, 257)].iter() {
+            let mut ir_code = IrCode::new(&Program::from_string(&format!(",{}", "+".repeat(300))));
+            ir_code.overflow = Overflow::Trap;
+            ir_code.optimize();
+            set_buffered_input(vec![*input]);
+            let brainfuck = ir_code.compile(IoFn::buffered());
+
+            assert_eq!(brainfuck.execute(), Err(RuntimeError::Overflow(Location { line: 1, column: *column })));
+        }
+    }
+
+    #[test]
+    fn sizes_tape_from_pointer_bounds() {
+        let mut ir_code = IrCode::new(&Program::from_string(",>+<[->>+<<]>>."));
+        ir_code.optimize();
+        set_buffered_input(vec![5]);
+        let brainfuck = ir_code.compile(IoFn::buffered());
+        assert_eq!(brainfuck.tape_size, 3);
+
+        let mut memory = vec![0; 3];
+        brainfuck.execute_with_memory(&mut memory).unwrap();
+        assert_eq!(memory, vec![0, 1, 5]);
+        assert_eq!(take_buffered_output(), vec![5]);
+
+        /* scan loop may reach any cell */
+        let mut ir_code = IrCode::new(&Program::from_string(",[>]"));
+        ir_code.optimize();
+        assert_eq!(ir_code.compile(IoFn::std()).tape_size, MAX_MEMORY);
+    }
+
+    #[test]
+    fn trapping_leaves_cell_at_boundary() {
+        /* like the interpreter, which stops before changing the cell */
+        let mut ir_code = IrCode::new(&Program::from_string("+++>--"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut memory = vec![0; MAX_MEMORY];
+
+        assert_eq!(ir_code.compile(IoFn::std()).execute_with_memory(&mut memory), Err(RuntimeError::Underflow(Location { line: 1, column: 5 })));
+        assert_eq!(memory[..2], [3, 0]);
+    }
+
+    #[test]
+    fn multiplies_into_far_cells() {
+        /* cells more than 128 cells away must not wrap around to nearer ones */
+        let source = format!("{},[-{}+{}]{}.", ">".repeat(200), ">".repeat(130), "<".repeat(130), ">".repeat(130));
+        let mut ir_code = IrCode::new(&Program::from_string(&source));
+        ir_code.optimize();
+        set_buffered_input(vec![3]);
+        let brainfuck = ir_code.compile(IoFn::buffered());
+
+        brainfuck.execute().unwrap();
+
+        assert_eq!(take_buffered_output(), vec![3]);
+    }
+
     #[test]
     fn can_run_pi_bf() {
         let pi_program = Program::from_string(">  +++++ +++++ +++++
@@ -205,11 +650,11 @@ mod test {
 <<<+<->>>>[>+<<<+++++++++<->>>-]<<<<<[>>+<<-]+<[->-<]>[>>.<<<<[+.[-]]>>-]>[>>.<<
 -]>[-]>[-]>>>[>>[<<<<<<<<+>>>>>>>>-]<<-]]>>[-]<<<[-]<<<<<<<<]++++++++++.");
         let mut ir_code = IrCode::new(&pi_program);
-        let brainfuck = ir_code.compile(IoFn { putchar_ptr: value_putchar as usize, getchar_ptr: getchar as usize });
+        let brainfuck = ir_code.compile(IoFn { putchar_ptr: value_putchar as *const () as usize, getchar_ptr: getchar as *const () as usize, write_ptr: value_write as *const () as usize });
 
         unsafe { OUTPUT_IDX = 0; }
 
-        brainfuck.execute();
+        brainfuck.execute().unwrap();
 
         assert_eq!(unsafe { OUTPUT[0] }, b'3');
         assert_eq!(unsafe { OUTPUT[1] }, b'.');
@@ -0,0 +1,180 @@
+use std::fmt::Write;
+
+use crate::ir::{IrCode, IrNode, IrOp, Optimizations};
+
+/// Optimizations recognising loop patterns and colors highlighting ops they created.
+const PATTERNS: [(Optimizations, &str); 5] = [
+    (Optimizations::CLEAR_LOOP, "lightblue"),
+    (Optimizations::SCAN_LOOP, "plum"),
+    (Optimizations::MULTIPLICATION_LOOP, "palegreen"),
+    (Optimizations::COUNTER_LOOP, "khaki"),
+    (Optimizations::PARTIAL_EVALUATION, "lightgrey"),
+];
+
+/// Edge not connected to its target yet: source node and label.
+type Pending = (String, Option<&'static str>);
+
+/// Builder of control-flow graph of one IrCode in DOT language.
+struct Graph {
+    out: String,
+    /* prefix of node names, unique for each graph */
+    prefix: String,
+    nodes: usize,
+}
+
+impl Graph {
+    fn node(&mut self, label: &str, attributes: &str) -> String {
+        let name = format!("{}{}", self.prefix, self.nodes);
+        self.nodes += 1;
+        writeln!(self.out, "    {} [label=\"{}\"{}];", name, label, attributes).unwrap();
+        name
+    }
+
+    fn connect(&mut self, pending: Vec<Pending>, target: &str, attributes: &str) {
+        for (source, label) in pending {
+            match label {
+                Some(label) => writeln!(self.out, "    {} -> {} [label=\"{}\"{}];", source, target, label, attributes),
+                None => writeln!(self.out, "    {} -> {} [{}];", source, target, attributes.trim_start_matches(", ")),
+            }.unwrap();
+        }
+    }
+
+    /// Emits basic block of straight-line `ops`, returns edges leaving it.
+    fn basic_block(&mut self, ops: &mut Vec<&IrNode>, pending: Vec<Pending>) -> Vec<Pending> {
+        if ops.is_empty() { return pending; }
+
+        let mut label = String::new();
+        let mut optimizations = Optimizations::empty();
+        for node in ops.drain(..) {
+            label.push_str(&escape(&node.op.to_string()));
+            let patterns: Vec<_> = PATTERNS.iter()
+                .filter(|(flag, _)| node.origin.optimizations.contains(*flag))
+                .flat_map(|(flag, _)| flag.names())
+                .collect();
+            if !patterns.is_empty() {
+                write!(label, "  # {}", patterns.join(", ")).unwrap();
+            }
+            label.push_str("\\l");
+            optimizations |= node.origin.optimizations;
+        }
+
+        let attributes = match PATTERNS.iter().find(|(flag, _)| optimizations.contains(*flag)) {
+            Some((_, color)) => format!(", style=filled, fillcolor={}", color),
+            None => String::new(),
+        };
+        let name = self.node(&label, &attributes);
+        self.connect(pending, &name, "");
+        vec![(name, None)]
+    }
+
+    /// Emits nodes of `block` entered by `pending` edges, returns edges leaving it.
+    fn block(&mut self, block: &[IrNode], mut pending: Vec<Pending>) -> Vec<Pending> {
+        let mut ops = Vec::new();
+
+        for node in block {
+            let body = match &node.op {
+                IrOp::Loop(body) | IrOp::IfNonZero(body) => body,
+                _ => {
+                    ops.push(node);
+                    continue;
+                }
+            };
+
+            pending = self.basic_block(&mut ops, pending);
+            let is_loop = matches!(node.op, IrOp::Loop(_));
+            let attributes = if node.origin.optimizations.contains(Optimizations::CONDITIONAL) {
+                ", shape=diamond, style=filled, fillcolor=orange"
+            } else {
+                ", shape=diamond"
+            };
+            let header = self.node(if is_loop { "loop" } else { "if" }, attributes);
+            self.connect(pending, &header, "");
+
+            let exits = self.block(body, vec![(header.clone(), Some("nonzero"))]);
+            pending = vec![(header.clone(), Some("zero"))];
+            if is_loop {
+                self.connect(exits, &header, ", style=dashed");
+            } else {
+                pending.extend(exits);
+            }
+        }
+
+        self.basic_block(&mut ops, pending)
+    }
+}
+
+/// Escapes text for DOT string.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Control-flow graphs of `graphs` (each with its title) in DOT language, side by side.
+/// Basic blocks of straight-line ops are nodes, ops created by recognised loop patterns
+/// are highlighted.
+pub fn to_dot(graphs: &[(&str, &IrCode)]) -> String {
+    let mut out = String::from("digraph ir {\n    node [shape=box, fontname=monospace];\n");
+
+    for (idx, (title, ir_code)) in graphs.iter().enumerate() {
+        let mut graph = Graph { out: String::new(), prefix: format!("g{}n", idx), nodes: 0 };
+        let start = graph.node("start", ", shape=oval");
+        let exits = graph.block(&ir_code.ops, vec![(start, None)]);
+        let end = graph.node("end", ", shape=oval");
+        graph.connect(exits, &end, "");
+
+        writeln!(out, "  subgraph cluster_{} {{\n    label=\"{}\";", idx, escape(title)).unwrap();
+        out.push_str(&graph.out);
+        out.push_str("  }\n");
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::Program;
+    use crate::dot::to_dot;
+    use crate::ir::IrCode;
+
+    #[test]
+    fn exports_control_flow() {
+        let mut ir_code = IrCode::new(&Program::from_string(",[->+<]>.[-]<+[.,]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+
+        assert_eq!(to_dot(&[("optimized", &ir_code)]), "digraph ir {\n    node [shape=box, fontname=monospace];\n  \
+            subgraph cluster_0 {\n    label=\"optimized\";\n    \
+            g0n0 [label=\"start\", shape=oval];\n    \
+            g0n1 [label=\"read\\l\
+                mulcopy +1, *1  # multiplication-loop\\l\
+                write +1\\l\
+                set +1, 0  # clear-loop\\l\
+                set 1\\l\", style=filled, fillcolor=lightblue];\n    \
+            g0n0 -> g0n1 [];\n    \
+            g0n2 [label=\"loop\", shape=diamond];\n    \
+            g0n1 -> g0n2 [];\n    \
+            g0n3 [label=\"write\\lread\\l\"];\n    \
+            g0n2 -> g0n3 [label=\"nonzero\"];\n    \
+            g0n3 -> g0n2 [style=dashed];\n    \
+            g0n4 [label=\"end\", shape=oval];\n    \
+            g0n2 -> g0n4 [label=\"zero\"];\n  \
+            }\n}\n");
+    }
+
+    #[test]
+    fn exports_conditionals_side_by_side() {
+        let program = Program::from_string(",[>+<[-]]");
+        let unoptimized = IrCode::new(&program);
+        let mut optimized = IrCode::new(&program);
+        optimized.eval_budget = 0;
+        optimized.optimize();
+        let dot = to_dot(&[("unoptimized", &unoptimized), ("optimized", &optimized)]);
+
+        assert!(dot.contains("subgraph cluster_0 {\n    label=\"unoptimized\";"));
+        assert!(dot.contains("subgraph cluster_1 {\n    label=\"optimized\";"));
+        assert!(dot.contains("g1n2 [label=\"if\", shape=diamond, style=filled, fillcolor=orange];"));
+        /* body of if continues after it, like skipped body */
+        assert!(dot.contains("g1n3 -> g1n4 [];"));
+        assert!(dot.contains("g1n2 -> g1n4 [label=\"zero\"];"));
+    }
+}
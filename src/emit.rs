@@ -0,0 +1,346 @@
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+
+use crate::brainfuck::{Location, Overflow};
+use crate::ir::{IrCode, IrNode, IrOp, KnownCells, iterations};
+
+/// Op which cannot be lowered to brainfuck without a temporary cell.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UnsupportedOp {
+    pub location: Location,
+    pub message: String,
+}
+
+impl Display for UnsupportedOp {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn unsupported(node: &IrNode, message: &str) -> Result<(), UnsupportedOp> {
+    Err(UnsupportedOp { location: node.origin.location, message: message.to_owned() })
+}
+
+/// Whether cell at `offset` is overwritten by `block` before it is read, so that its
+/// value may be destroyed. Follows rules of dead store elimination.
+fn overwritten(block: &[IrNode], mut offset: i32) -> bool {
+    for node in block {
+        let mut touched = BTreeSet::new();
+        match &node.op {
+            IrOp::Move(x) => offset -= *x,
+            IrOp::SetIndirect(t, _) | IrOp::Read(t) if *t == offset => return true,
+            IrOp::ScanRight(_) | IrOp::ScanLeft(_) => return false,
+            IrOp::Loop(body) | IrOp::IfNonZero(body) => {
+                if offset == 0 || !IrCode::touched_cells(body, &mut touched) || touched.contains(&offset) {
+                    return false;
+                }
+            }
+            _ => {
+                IrCode::touched_cells(std::slice::from_ref(node), &mut touched);
+                if touched.contains(&offset) { return false; }
+            }
+        }
+    }
+    false
+}
+
+/// Cell whose value is overwritten by `block` before it is read, nearest to `cursor`.
+fn dead_cell(block: &[IrNode], cursor: i32) -> Option<i32> {
+    let mut shift = 0;
+    let mut stored = Vec::new();
+    for node in block {
+        match node.op {
+            IrOp::Move(x) => shift += x,
+            IrOp::SetIndirect(offset, _) | IrOp::Read(offset) => stored.push(offset + shift),
+            IrOp::Loop(_) | IrOp::IfNonZero(_) | IrOp::ScanRight(_) | IrOp::ScanLeft(_) => break,
+            _ => {}
+        }
+    }
+    stored.into_iter().filter(|t| overwritten(block, *t)).min_by_key(|t| (t - cursor).abs())
+}
+
+/// Builder of brainfuck source. Ops address cells by offset, so the brainfuck pointer
+/// only moves to cells which are used and is moved back to the IR pointer before loops.
+struct Emitter {
+    out: String,
+    overflow: Overflow,
+    /* position of brainfuck pointer relative to IR pointer */
+    cursor: i32,
+}
+
+impl Emitter {
+    fn repeat(&mut self, c: char, count: usize) {
+        self.out.extend(std::iter::repeat_n(c, count));
+    }
+
+    fn move_to(&mut self, offset: i32) {
+        let shift = offset - self.cursor;
+        self.repeat(if shift > 0 { '>' } else { '<' }, shift.unsigned_abs() as usize);
+        self.cursor = offset;
+    }
+
+    /// Adds `delta` to the cell, wrapping cells use the shorter of `+` and `-` runs.
+    fn add(&mut self, delta: u8) {
+        match self.overflow {
+            Overflow::Wrap if delta > 128 => self.repeat('-', 256 - delta as usize),
+            _ => self.repeat('+', delta as usize),
+        }
+    }
+
+    /// Changes the cell from `from` to `to` without overflowing it.
+    fn change(&mut self, from: u8, to: u8) {
+        match self.overflow {
+            Overflow::Wrap => self.add(to.wrapping_sub(from)),
+            _ if to >= from => self.repeat('+', (to - from) as usize),
+            _ => self.repeat('-', (from - to) as usize),
+        }
+    }
+
+    /// Emits loop over `ops` of `block` starting at `idx`: `Iterations` of the source cell
+    /// and `MulCopy` ops from it become one copy loop, which clears the source. Returns
+    /// number of ops emitted.
+    fn copy_loop(&mut self, block: &[IrNode], idx: usize, known: &mut KnownCells) -> Result<usize, UnsupportedOp> {
+        let (source, step) = match block[idx].op {
+            IrOp::Iterations(offset, step) => (offset, step),
+            IrOp::MulCopy(source, _, _) => (source, 255),
+            _ => unreachable!("only multiplication ops start copy loop"),
+        };
+        let mut end = idx + matches!(block[idx].op, IrOp::Iterations(..)) as usize;
+        while let Some(IrOp::MulCopy(s, t, _)) = block.get(end).map(|t| &t.op) {
+            if *s != source || *t == source { break; }
+            end += 1;
+        }
+        if !overwritten(&block[end..], source) {
+            unsupported(&block[idx], "multiplication keeps its source cell, which needs a temporary cell")?;
+        }
+
+        let count = known.get(source).and_then(|t| iterations(t, step));
+        self.move_to(source);
+        self.out.push('[');
+        self.add(step);
+        for node in &block[idx..end] {
+            if let IrOp::MulCopy(_, target, factor) = node.op {
+                self.move_to(target);
+                self.add(factor);
+                let value = count.zip(known.get(target)).map(|(count, value)| value.wrapping_add(count.wrapping_mul(factor)));
+                known.set(target, value);
+            }
+        }
+        self.move_to(source);
+        self.out.push(']');
+        known.set(source, Some(0));
+
+        Ok(end - idx)
+    }
+
+    fn block(&mut self, block: &[IrNode], known: &mut KnownCells) -> Result<(), UnsupportedOp> {
+        let mut idx = 0;
+
+        while idx < block.len() {
+            let node = &block[idx];
+            idx += 1;
+
+            match &node.op {
+                IrOp::Move(x) => {
+                    self.cursor -= *x;
+                    known.shift(*x);
+                }
+                IrOp::Add(offset, x) => {
+                    self.move_to(*offset);
+                    match *x {
+                        x if self.overflow == Overflow::Wrap => self.add(x as u8),
+                        x if x >= 0 => self.repeat('+', x as usize),
+                        x => self.repeat('-', x.unsigned_abs() as usize),
+                    }
+                    known.set(*offset, known.get(*offset).and_then(|t| self.overflow.add_signed(t, *x)));
+                }
+                IrOp::SetIndirect(offset, value) => {
+                    self.move_to(*offset);
+                    match known.get(*offset) {
+                        Some(current) => self.change(current, *value),
+                        None => {
+                            self.out.push_str("[-]");
+                            self.add(*value);
+                        }
+                    }
+                    known.set(*offset, Some(*value));
+                }
+                IrOp::MulCopy(..) | IrOp::Iterations(..) => idx += self.copy_loop(block, idx - 1, known)? - 1,
+                IrOp::Write(offset) => {
+                    self.move_to(*offset);
+                    self.out.push('.');
+                }
+                IrOp::WriteConst(bytes) => {
+                    /* bytes are written from a cell with known value, which is restored, or
+                     * from a cell overwritten later, which is cleared first */
+                    let (offset, value) = match (known.nearest(self.cursor), dead_cell(&block[idx..], self.cursor)) {
+                        (Some(t), _) => t,
+                        (None, Some(offset)) => {
+                            self.move_to(offset);
+                            self.out.push_str("[-]");
+                            (offset, 0)
+                        }
+                        (None, None) => return unsupported(node, "constant write needs a cell with known value"),
+                    };
+                    self.move_to(offset);
+                    let last = bytes.iter().fold(value, |current, byte| {
+                        self.change(current, *byte);
+                        self.out.push('.');
+                        *byte
+                    });
+                    if known.get(offset).is_some() {
+                        self.change(last, value);
+                    } else {
+                        known.set(offset, Some(last));
+                    }
+                }
+                IrOp::Read(offset) => {
+                    self.move_to(*offset);
+                    self.out.push(',');
+                    known.set(*offset, None);
+                }
+                IrOp::Loop(body) => {
+                    self.move_to(0);
+                    self.out.push('[');
+                    /* values at the start of every iteration */
+                    let mut entry = known.clone();
+                    let mut written = BTreeSet::new();
+                    if IrCode::written_cells(body, &mut written) {
+                        written.into_iter().for_each(|offset| entry.set(offset, None));
+                    } else {
+                        entry.forget();
+                    }
+                    entry.set(0, None);
+                    self.block(body, &mut entry.clone())?;
+                    self.move_to(0);
+                    self.out.push(']');
+                    *known = entry;
+                    known.set(0, Some(0));
+                }
+                IrOp::IfNonZero(body) => {
+                    self.move_to(0);
+                    self.out.push('[');
+                    let mut taken = known.clone();
+                    taken.set(0, None);
+                    self.block(body, &mut taken)?;
+                    self.move_to(0);
+                    /* loop with body which clears its cell runs once */
+                    if taken.get(0) != Some(0) {
+                        unsupported(node, "conditional may leave its cell nonzero")?;
+                    }
+                    self.out.push(']');
+                    IrCode::join_conditional(known, &taken, body);
+                }
+                IrOp::ScanRight(stride) | IrOp::ScanLeft(stride) => {
+                    self.move_to(0);
+                    self.out.push('[');
+                    self.repeat(if matches!(node.op, IrOp::ScanRight(_)) { '>' } else { '<' }, *stride as usize);
+                    self.out.push(']');
+                    known.forget();
+                    known.set(0, Some(0));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl IrCode {
+    /// Lowers IR back to brainfuck: multiplications become copy loops, sets clear the
+    /// cell first unless its value is known and wrapping cells use the shorter of `+`
+    /// and `-` runs. Fails for ops which would need a temporary cell.
+    pub fn to_brainfuck(&self) -> Result<String, UnsupportedOp> {
+        let mut emitter = Emitter { out: String::new(), overflow: self.overflow, cursor: 0 };
+        emitter.block(&self.ops, &mut KnownCells::zeroed())?;
+        Ok(emitter.out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Program, Overflow};
+    use crate::generator::Generator;
+    use crate::ir::IrCode;
+    use crate::verify::{run_interpreter, compare};
+
+    fn lower(text: &str, overflow: Overflow) -> String {
+        let mut ir_code = IrCode::parse(text).unwrap();
+        ir_code.overflow = overflow;
+        match ir_code.to_brainfuck() {
+            Ok(source) => source,
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// Checks that optimized program lowered to brainfuck runs like the original one.
+    fn assert_round_trip(source: &str, input: &[u8], overflow: Overflow) {
+        let program = Program::from_string(source);
+        let mut ir_code = IrCode::new(&program);
+        ir_code.overflow = overflow;
+        ir_code.optimize();
+        let lowered = Program::from_string(&ir_code.to_brainfuck().unwrap());
+
+        let (expected, _) = run_interpreter(&program, input, overflow);
+        let (mut actual, _) = run_interpreter(&lowered, input, overflow);
+        /* locations of the lowered program are unrelated to the original ones */
+        if let (Err(e), Err(a)) = (expected.result, actual.result) {
+            if std::mem::discriminant(&e) == std::mem::discriminant(&a) {
+                actual.result = expected.result;
+            }
+        }
+        assert_eq!(compare(&expected, &actual), None, "{}", source);
+    }
+
+    #[test]
+    fn lowers_ops() {
+        assert_eq!(lower("add +2, 3\nsub 1\nmove +1\nwrite +1\nread -1", Overflow::Wrap), ">>+++<<->>.<<,");
+        assert_eq!(lower("read\nadd 250\nsub +1, 200", Overflow::Wrap), ",------>++++++++++++++++++++++++++++++++++++++++++++++++++++++++");
+        assert_eq!(lower("read\nadd 250", Overflow::Saturate).len(), 251);
+        /* value of untouched cells is known */
+        assert_eq!(lower("read\nset 2\nset +1, 255\nset +1, 1", Overflow::Wrap), ",[-]++>-++");
+        assert_eq!(lower("scanright 2\nloop {\n    write\n}\nwrite -1", Overflow::Wrap), "[>>][.]<.");
+    }
+
+    #[test]
+    fn lowers_multiplications_to_copy_loops() {
+        assert_eq!(lower("read\nmulcopy +1, *2\nmulcopy -1, *255\nset 0", Overflow::Wrap), ",[->++<<->]");
+        assert_eq!(lower("read\niterations 254\nmulcopy +2, *3\nset 1", Overflow::Wrap), ",[-->>+++<<]+");
+        assert_eq!(lower("read\nmulcopy +1, *2\nwrite", Overflow::Wrap),
+                   "2:1: multiplication keeps its source cell, which needs a temporary cell");
+    }
+
+    #[test]
+    fn lowers_constant_writes_with_known_cell() {
+        assert_eq!(lower("write \"AB\"\nset 2", Overflow::Wrap), format!("{}.+.{}++", "+".repeat(65), "-".repeat(66)));
+        assert_eq!(lower("read\nloop {\n    write \"\\x01\"\n    set 0\n}\nwrite \"\\x02\"", Overflow::Wrap),
+                   ",[>+.-<[-]]++.--");
+        assert_eq!(lower("read\nloop {\n    write \"\\x01\"\n    move +1\n}", Overflow::Wrap), "3:5: constant write needs a cell with known value");
+    }
+
+    #[test]
+    fn lowers_conditionals() {
+        assert_eq!(lower("read\nif {\n    write\n    set 0\n}", Overflow::Wrap), ",[.[-]]");
+        assert_eq!(lower("read\nif {\n    write\n}", Overflow::Wrap), "2:1: conditional may leave its cell nonzero");
+    }
+
+    #[test]
+    fn round_trips_sample_programs() {
+        for file in &["hello_world.bf", "sierpinski.bf", "squares.bf"] {
+            let path = format!("{}/sample_programs/{}", env!("CARGO_MANIFEST_DIR"), file);
+            assert_round_trip(&std::fs::read_to_string(path).unwrap(), b"", Overflow::Wrap);
+        }
+    }
+
+    #[test]
+    fn round_trips_generated_programs() {
+        for overflow in &[Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+            for seed in 0..100 {
+                let mut generator = Generator::new(seed);
+                generator.overflow = *overflow;
+                let generated = generator.generate();
+                assert_round_trip(&generated.source, &generated.input, *overflow);
+            }
+        }
+    }
+}
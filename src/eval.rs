@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+
+use crate::brainfuck::{Overflow, MAX_MEMORY};
+use crate::ir::{IrNode, IrOp, iterations};
+
+/// Reason why `Evaluator` stopped before an op.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Halt {
+    /* op reads input, which is not known at compile time (evaluator has no input) */
+    Input,
+    /* step budget was exhausted */
+    Budget,
+    /* op would trap or access cell outside of memory, it is left to run time */
+    Fault,
+}
+
+/// Executes IrCode at compile time, or as an IR interpreter when given input. Ops which
+/// halt the evaluation do not change its state, loops may be left half way and must be
+/// restored by caller.
+#[derive(Clone)]
+pub struct Evaluator {
+    pub memory: Vec<u8>,
+    /* relative to the start of memory, may point outside of it */
+    pub pointer: isize,
+    pub output: Vec<u8>,
+    /* ops (or loop iterations) which can be executed before halting with Halt::Budget */
+    pub budget: usize,
+    pub overflow: Overflow,
+    /* bytes read by Read ops, reading past its end gives zero */
+    pub input: Option<VecDeque<u8>>,
+}
+
+impl Evaluator {
+    pub fn new(overflow: Overflow, budget: usize) -> Self {
+        Evaluator { memory: vec![0; MAX_MEMORY], pointer: 0, output: Vec::new(), budget, overflow, input: None }
+    }
+
+    pub fn with_input(self, input: Vec<u8>) -> Self {
+        Evaluator { input: Some(input.into()), ..self }
+    }
+
+    /// Address of cell at `offset` from the pointer if it is in memory.
+    fn address(&self, offset: i32) -> Result<usize, Halt> {
+        let address = self.pointer + offset as isize;
+        if address >= 0 && (address as usize) < self.memory.len() { Ok(address as usize) } else { Err(Halt::Fault) }
+    }
+
+    fn step(&mut self) -> Result<(), Halt> {
+        self.budget = self.budget.checked_sub(1).ok_or(Halt::Budget)?;
+        Ok(())
+    }
+
+    pub fn execute_block(&mut self, block: &[IrNode]) -> Result<(), Halt> {
+        block.iter().try_for_each(|node| self.execute(node))
+    }
+
+    pub fn execute(&mut self, node: &IrNode) -> Result<(), Halt> {
+        self.step()?;
+
+        match &node.op {
+            IrOp::Move(x) => self.pointer += *x as isize,
+            IrOp::Add(offset, x) => {
+                let address = self.address(*offset)?;
+                self.memory[address] = self.overflow.add_signed(self.memory[address], *x).ok_or(Halt::Fault)?;
+            }
+            IrOp::SetIndirect(offset, x) => {
+                let address = self.address(*offset)?;
+                self.memory[address] = *x;
+            }
+            IrOp::MulCopy(source, target, factor) => {
+                let (source, target) = (self.address(*source)?, self.address(*target)?);
+                self.memory[target] = self.memory[target].wrapping_add(self.memory[source].wrapping_mul(*factor));
+            }
+            IrOp::Iterations(offset, step) => {
+                let address = self.address(*offset)?;
+                self.memory[address] = iterations(self.memory[address], *step).ok_or(Halt::Fault)?;
+            }
+            IrOp::Write(offset) => {
+                let address = self.address(*offset)?;
+                self.output.push(self.memory[address]);
+            }
+            IrOp::WriteConst(bytes) => self.output.extend_from_slice(bytes),
+            IrOp::Read(offset) => {
+                let address = self.address(*offset)?;
+                let input = self.input.as_mut().ok_or(Halt::Input)?;
+                self.memory[address] = input.pop_front().unwrap_or(0);
+            }
+            IrOp::ScanRight(stride) => self.scan(*stride as isize)?,
+            IrOp::ScanLeft(stride) => self.scan(-(*stride as isize))?,
+            IrOp::Loop(body) => {
+                while self.memory[self.address(0)?] != 0 {
+                    self.execute_block(body)?;
+                    self.step()?;
+                }
+            }
+            IrOp::IfNonZero(body) => {
+                if self.memory[self.address(0)?] != 0 {
+                    self.execute_block(body)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn scan(&mut self, stride: isize) -> Result<(), Halt> {
+        let start = self.pointer;
+        while self.memory[self.address(0)?] != 0 {
+            self.pointer += stride;
+            if let Err(halt) = self.step() {
+                self.pointer = start;
+                return Err(halt);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Program, Overflow};
+    use crate::eval::{Evaluator, Halt};
+    use crate::ir::{IrCode, IrNode, IrOp, Origin};
+
+    fn evaluate(source: &str, overflow: Overflow, budget: usize) -> (Evaluator, Result<(), Halt>) {
+        let mut ir_code = IrCode::new(&Program::from_string(source));
+        ir_code.overflow = overflow;
+        let mut evaluator = Evaluator::new(overflow, budget);
+        let result = evaluator.execute_block(&ir_code.ops);
+        (evaluator, result)
+    }
+
+    #[test]
+    fn evaluates_programs() {
+        let (evaluator, result) = evaluate("++++++[>++++++++<-]>+.+.<<", Overflow::Wrap, 1000);
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(evaluator.output, b"12");
+        assert_eq!(evaluator.memory[1], b'2');
+        assert_eq!(evaluator.pointer, -1);
+    }
+
+    #[test]
+    fn evaluates_conditionals() {
+        let node = |op| IrNode { op, origin: Origin::default() };
+        let ops = vec![
+            node(IrOp::Add(0, 1)),
+            node(IrOp::IfNonZero(vec![node(IrOp::Write(0)), node(IrOp::SetIndirect(0, 0))])),
+            node(IrOp::IfNonZero(vec![node(IrOp::Add(0, 5))])),
+            node(IrOp::Write(0)),
+        ];
+        let mut evaluator = Evaluator::new(Overflow::Wrap, 1000);
+
+        assert_eq!(evaluator.execute_block(&ops), Ok(()));
+        assert_eq!(evaluator.output, vec![1, 0]);
+    }
+
+    #[test]
+    fn reads_input() {
+        let ir_code = IrCode::new(&Program::from_string(",[.,]"));
+        let mut evaluator = Evaluator::new(Overflow::Wrap, 1000).with_input(b"abc".to_vec());
+
+        assert_eq!(evaluator.execute_block(&ir_code.ops), Ok(()));
+        assert_eq!(evaluator.output, b"abc");
+    }
+
+    #[test]
+    fn halts_evaluation() {
+        assert_eq!(evaluate("+.,", Overflow::Wrap, 1000).1, Err(Halt::Input));
+        assert_eq!(evaluate("+[]", Overflow::Wrap, 1000).1, Err(Halt::Budget));
+        assert_eq!(evaluate("-", Overflow::Trap, 1000).1, Err(Halt::Fault));
+        assert_eq!(evaluate("<+", Overflow::Wrap, 1000).1, Err(Halt::Fault));
+    }
+}
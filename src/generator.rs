@@ -0,0 +1,221 @@
+use crate::brainfuck::Overflow;
+
+/// Generator of random well-formed brainfuck programs which always terminate and keep
+/// the pointer inside of `cells`. Programs are built from runs of straight-line
+/// instructions, clear loops, scan loops and counter loops which set their cell to a
+/// multiple of their step before the loop.
+pub struct Generator {
+    /* state of splitmix64 */
+    state: u64,
+    /* cells the pointer stays in */
+    pub cells: usize,
+    /* approximate number of pieces generated at top level */
+    pub length: usize,
+    pub overflow: Overflow,
+}
+
+/// Program made by `Generator` with input long enough for all its reads.
+pub struct Generated {
+    pub source: String,
+    pub input: Vec<u8>,
+}
+
+/// Pointer position and limits while generating a block.
+#[derive(Copy, Clone)]
+struct Position {
+    /* lowest and highest cell the pointer may be at (scan loops make it unknown) */
+    low: usize,
+    high: usize,
+    /* values of `low` at counters of enclosing loops, which must not be changed.
+     * bodies contain no scan loops, so `low` differs from them iff the pointer does */
+    counters: [usize; MAX_DEPTH],
+    /* product of iterations of enclosing loops */
+    repeats: usize,
+    /* number of enclosing loops */
+    depth: usize,
+}
+
+const MAX_DEPTH: usize = 3;
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Generator { state: seed, cells: 16, length: 20, overflow: Overflow::Wrap }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in `low..=high`.
+    fn range(&mut self, low: usize, high: usize) -> usize {
+        low + (self.next_u64() % (high - low + 1) as u64) as usize
+    }
+
+    pub fn generate(&mut self) -> Generated {
+        let mut source = String::new();
+        let mut reads = 0;
+        let mut position = Position { low: 0, high: 0, counters: [0; MAX_DEPTH], repeats: 1, depth: 0 };
+
+        for _ in 0..self.length {
+            self.piece(&mut source, &mut position, &mut reads);
+        }
+
+        let input = (0..reads).map(|_| self.next_u64() as u8).collect();
+        Generated { source, input }
+    }
+
+    /// Appends random piece of program, counting `reads` it may execute.
+    fn piece(&mut self, source: &mut String, position: &mut Position, reads: &mut usize) {
+        match self.range(0, 9) {
+            0..=4 => self.straight(source, position, reads),
+            5 | 6 if position.depth < MAX_DEPTH => self.counter_loop(source, position, reads),
+            7 if !self.protects(position) => {
+                /* only wrapping cells reach zero from both directions */
+                source.push_str(if self.overflow == Overflow::Wrap && self.range(0, 1) == 0 { "[+]" } else { "[-]" });
+            }
+            8 if position.depth == 0 => self.scan_loop(source, position),
+            _ => self.straight(source, position, reads),
+        }
+    }
+
+    /// Whether the pointer is at a counter of an enclosing loop.
+    fn protects(&self, position: &Position) -> bool {
+        position.counters[..position.depth].contains(&position.low)
+    }
+
+    /// Moves the pointer by random amount keeping it inside of tape.
+    fn shift(&mut self, source: &mut String, position: &mut Position) {
+        let target = self.range(0, self.cells - 1 - (position.high - position.low));
+        if target > position.low {
+            source.push_str(&">".repeat(target - position.low));
+        } else {
+            source.push_str(&"<".repeat(position.low - target));
+        }
+        position.high = target + (position.high - position.low);
+        position.low = target;
+    }
+
+    fn straight(&mut self, source: &mut String, position: &mut Position, reads: &mut usize) {
+        for _ in 0..self.range(1, 8) {
+            match self.range(0, 5) {
+                0 => self.shift(source, position),
+                /* trapping cells would end most programs early, so changes are small */
+                1 | 2 if !self.protects(position) => {
+                    let c = if self.range(0, 1) == 0 { "+" } else { "-" };
+                    source.push_str(&c.repeat(self.range(1, 12)));
+                }
+                3 if !self.protects(position) => {
+                    source.push(',');
+                    *reads += position.repeats;
+                }
+                _ => source.push('.'),
+            }
+        }
+    }
+
+    /// Loop which changes its cell by step and runs 1 to 4 times. Its body moves the
+    /// pointer back and does not touch the cell otherwise.
+    fn counter_loop(&mut self, source: &mut String, position: &mut Position, reads: &mut usize) {
+        if self.protects(position) { return; }
+
+        let step = self.range(1, 4);
+        let iterations = self.range(1, 4);
+        let up = self.overflow == Overflow::Wrap && self.range(0, 1) == 0;
+        /* cell is cleared first so that the number of iterations is known */
+        source.push_str("[-]");
+        source.push_str(&(if up { "-" } else { "+" }).repeat(step * iterations));
+        source.push('[');
+        source.push_str(&(if up { "+" } else { "-" }).repeat(step));
+
+        let start = *position;
+        let mut body = Position {
+            counters: start.counters,
+            repeats: position.repeats * iterations,
+            depth: position.depth + 1,
+            ..start
+        };
+        body.counters[start.depth] = start.low;
+        for _ in 0..self.range(1, 3) {
+            self.piece(source, &mut body, reads);
+        }
+        if body.low > start.low {
+            source.push_str(&"<".repeat(body.low - start.low));
+        } else {
+            source.push_str(&">".repeat(start.low - body.low));
+        }
+        source.push(']');
+        *position = start;
+    }
+
+    /// Scan loop over cells made zero before it, the pointer ends at one of them.
+    fn scan_loop(&mut self, source: &mut String, position: &mut Position) {
+        let stride = self.range(1, 2);
+        let right = self.range(0, 1) == 0;
+        if right && position.high + stride >= self.cells || !right && position.low < stride {
+            return;
+        }
+
+        /* clear the cell past the furthest possible position so the scan stops there */
+        let (there, back) = if right { (">", "<") } else { ("<", ">") };
+        source.push_str(&there.repeat(stride));
+        source.push_str("[-]");
+        source.push_str(&back.repeat(stride));
+        source.push('[');
+        source.push_str(&there.repeat(stride));
+        source.push(']');
+
+        if right {
+            position.high += stride;
+        } else {
+            position.low -= stride;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Program, Overflow};
+    use crate::generator::Generator;
+    use crate::interpreter::Interpreter;
+    use crate::ir::{IrCode, Optimizations};
+    use crate::observer::Profiler;
+    use crate::verify::verify;
+
+    #[test]
+    fn generates_same_program_for_seed() {
+        assert_eq!(Generator::new(7).generate().source, Generator::new(7).generate().source);
+        assert_ne!(Generator::new(7).generate().source, Generator::new(8).generate().source);
+    }
+
+    #[test]
+    fn generated_programs_terminate_inside_of_tape() {
+        for seed in 0..200 {
+            let generated = Generator::new(seed).generate();
+            let program = Program::from_string(&generated.source);
+            IrCode::new(&program);
+
+            let mut vm = Interpreter::new(&program, generated.input.as_slice(), std::io::sink())
+                .with_observer(Profiler::default());
+            assert_eq!(vm.interpret(), Ok(()), "{}", generated.source);
+            assert!(vm.observer.highest_address < 16, "{}", generated.source);
+        }
+    }
+
+    #[test]
+    fn optimized_jit_agrees_on_generated_programs() {
+        for overflow in &[Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+            for seed in 0..100 {
+                let mut generator = Generator::new(seed);
+                generator.overflow = *overflow;
+                let generated = generator.generate();
+                let program = Program::from_string(&generated.source);
+
+                assert!(verify(&program, &generated.input, *overflow, Optimizations::all()).is_empty(), "{}", generated.source);
+            }
+        }
+    }
+}
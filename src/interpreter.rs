@@ -1,36 +1,183 @@
+use std::collections::HashSet;
 use std::io::{Read, Write};
-use crate::brainfuck::{Program, Op, MAX_MEMORY};
-use std::num::Wrapping;
+use std::str::FromStr;
+use crate::brainfuck::{Program, Op, MAX_MEMORY, Overflow, RuntimeError, BalancedLoop};
+use crate::observer::{ExecutionObserver, NoopObserver, Io};
+use crate::tape::Tape;
+use crate::ir::iterations;
 
-pub struct Interpreter<'a, R: Read, W: Write> {
+/// Loops with more cells than this are not checked for cycles in machine state.
+const MAX_CYCLE_CELLS: isize = 8;
+/// Number of states recorded per loop entry after which cycle detection gives up.
+const MAX_CYCLE_STATES: usize = 1 << 16;
+
+/// How hard the interpreter tries to detect loops which never terminate.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum LoopDetection {
+    Off,
+    /// Loops whose body cannot change the loop cell nor the pointer, or changes the
+    /// cell by a step which never reaches zero from its value.
+    Static,
+    /// Also repeated states of cells touched by small loops (slower).
+    Cycles,
+}
+
+impl FromStr for LoopDetection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(LoopDetection::Off),
+            "static" => Ok(LoopDetection::Static),
+            "cycles" => Ok(LoopDetection::Cycles),
+            _ => Err(format!("unknown loop detection: {}", s)),
+        }
+    }
+}
+
+pub struct Interpreter<'a, R: Read, W: Write, O: ExecutionObserver = NoopObserver, T: Tape = [u8; MAX_MEMORY]> {
     pub program_counter: usize,
     pub memory_pointer: usize,
     pub program: &'a Program,
-    pub memory: [u8; MAX_MEMORY],
+    pub memory: T,
     pub input: R,
     pub output: W,
+    pub overflow: Overflow,
+    pub loop_detection: LoopDetection,
+    pub observer: O,
+    /* indexed by position of Op::JumpForward, filled on first use */
+    balanced_loops: Vec<Option<BalancedLoop>>,
+    scan_loops: Vec<Option<isize>>,
+    /* states of the cycle-checked loop seen since it was entered */
+    loop_states: HashSet<Vec<u8>>,
 }
 
 impl<'a, R: Read, W: Write> Interpreter<'a, R, W> {
+    pub fn new(program: &'a Program, input: R, output: W) -> Self {
+        Interpreter {
+            program_counter: 0,
+            memory_pointer: 0,
+            program,
+            memory: [0; MAX_MEMORY],
+            input,
+            output,
+            overflow: Overflow::Wrap,
+            loop_detection: LoopDetection::Static,
+            observer: NoopObserver,
+            balanced_loops: Vec::new(),
+            scan_loops: Vec::new(),
+            loop_states: HashSet::new(),
+        }
+    }
+}
+
+impl<'a, R: Read, W: Write, O: ExecutionObserver, T: Tape> Interpreter<'a, R, W, O, T> {
+    /// Replaces observer of this interpreter keeping rest of the state.
+    pub fn with_observer<U: ExecutionObserver>(self, observer: U) -> Interpreter<'a, R, W, U, T> {
+        Interpreter {
+            program_counter: self.program_counter,
+            memory_pointer: self.memory_pointer,
+            program: self.program,
+            memory: self.memory,
+            input: self.input,
+            output: self.output,
+            overflow: self.overflow,
+            loop_detection: self.loop_detection,
+            observer,
+            balanced_loops: self.balanced_loops,
+            scan_loops: self.scan_loops,
+            loop_states: self.loop_states,
+        }
+    }
+
+    /// Replaces memory of this interpreter keeping rest of the state.
+    pub fn with_tape<U: Tape>(self, memory: U) -> Interpreter<'a, R, W, O, U> {
+        Interpreter {
+            program_counter: self.program_counter,
+            memory_pointer: self.memory_pointer,
+            program: self.program,
+            memory,
+            input: self.input,
+            output: self.output,
+            overflow: self.overflow,
+            loop_detection: self.loop_detection,
+            observer: self.observer,
+            balanced_loops: self.balanced_loops,
+            scan_loops: self.scan_loops,
+            loop_states: self.loop_states,
+        }
+    }
+
     #[inline]
     pub fn memory_at(&self, address: usize) -> u8 {
-        self.memory[address]
+        self.memory.get(address)
     }
 
-    pub fn interpret(&mut self) {
+    pub fn interpret(&mut self) -> Result<(), RuntimeError> {
+        if self.loop_detection != LoopDetection::Off && self.balanced_loops.len() != self.program.instructions.len() {
+            self.balanced_loops = (0..self.program.instructions.len())
+                .map(|pc| match self.program.instructions[pc] {
+                    Op::JumpForward => self.program.balanced_loop(pc),
+                    _ => None
+                })
+                .collect();
+        }
+
+        if self.scan_loops.len() != self.program.instructions.len() {
+            self.scan_loops = (0..self.program.instructions.len())
+                .map(|pc| match self.program.instructions[pc] {
+                    Op::JumpForward => self.program.scan_loop(pc),
+                    _ => None
+                })
+                .collect();
+        }
+
         while self.program_counter < self.program.instructions.len() {
-            match &self.program.instructions[self.program_counter] {
+            let op = self.program.instructions[self.program_counter];
+            self.observer.on_step(self.program_counter, op);
+
+            match op {
                 Op::IncrementPtr => self.memory_pointer += 1,
                 Op::DecrementPtr => self.memory_pointer -= 1,
-                Op::IncrementMemory => self.memory[self.memory_pointer] = (Wrapping(self.memory[self.memory_pointer]) + Wrapping(1)).0,
-                Op::DecrementMemory => self.memory[self.memory_pointer] = (Wrapping(self.memory[self.memory_pointer]) - Wrapping(1)).0,
-                Op::ReadByte => self.memory[self.memory_pointer] = self.read_byte_from_input(),
-                Op::WriteByte => self.write_byte_to_output(self.memory_at(self.memory_pointer)),
-                Op::JumpForward => self.op_jump_forward(),
-                Op::JumpBackward => self.op_jump_backward()
+                Op::IncrementMemory => self.op_increment()?,
+                Op::DecrementMemory => self.op_decrement()?,
+                Op::ReadByte => {
+                    let byte = self.read_byte_from_input();
+                    self.observer.on_io(self.program_counter, Io::Read(byte));
+                    self.set_memory(byte)
+                }
+                Op::WriteByte => {
+                    let byte = self.memory_at(self.memory_pointer);
+                    self.write_byte_to_output(byte);
+                    self.observer.on_io(self.program_counter, Io::Write(byte));
+                }
+                Op::JumpForward => self.op_jump_forward()?,
+                Op::JumpBackward => self.op_jump_backward()?
             }
             self.program_counter += 1
         }
+
+        Ok(())
+    }
+
+    fn op_increment(&mut self) -> Result<(), RuntimeError> {
+        let value = self.overflow.add(self.memory_at(self.memory_pointer), 1)
+            .ok_or(RuntimeError::Overflow(self.program.locations[self.program_counter]))?;
+        self.set_memory(value);
+        Ok(())
+    }
+
+    fn op_decrement(&mut self) -> Result<(), RuntimeError> {
+        let value = self.overflow.sub(self.memory_at(self.memory_pointer), 1)
+            .ok_or(RuntimeError::Underflow(self.program.locations[self.program_counter]))?;
+        self.set_memory(value);
+        Ok(())
+    }
+
+    #[inline]
+    fn set_memory(&mut self, value: u8) {
+        self.memory.set(self.memory_pointer, value);
+        self.observer.on_cell_write(self.memory_pointer, value);
     }
 
     fn read_byte_from_input(&mut self) -> u8 {
@@ -46,17 +193,84 @@ impl<'a, R: Read, W: Write> Interpreter<'a, R, W> {
         self.output.write_all(&[byte]).expect("cannot write to output");
     }
 
-    fn op_jump_forward(&mut self) {
+    fn op_jump_forward(&mut self) -> Result<(), RuntimeError> {
         if self.memory_at(self.memory_pointer) == 0 {
             let end = self.program.find_matching_jump_end(self.program_counter);
             self.program_counter = end;
+        } else if let Some(stride) = self.scan_loops[self.program_counter] {
+            self.observer.on_loop_enter(self.program_counter);
+            self.scan(stride);
+        } else {
+            if let Some(body) = self.balanced_loop(self.program_counter) {
+                /* loop cell stays non-zero forever or wraps around without reaching zero,
+                 * like the JIT traps in counter loops; trapping cells of the body stop
+                 * the loop first */
+                let cell = self.memory_at(self.memory_pointer);
+                let traps = self.overflow == Overflow::Trap && body.changes_cells;
+                if (!body.changes_condition && !traps) || (self.overflow == Overflow::Wrap && iterations(cell, body.step).is_none()) {
+                    return Err(RuntimeError::InfiniteLoop(self.program.locations[self.program_counter]));
+                }
+                self.loop_states.clear();
+            }
+            self.observer.on_loop_enter(self.program_counter);
         }
+        Ok(())
     }
 
-    fn op_jump_backward(&mut self) {
+    fn op_jump_backward(&mut self) -> Result<(), RuntimeError> {
         if self.memory_at(self.memory_pointer) != 0 {
             let begin = self.program.find_matching_jump_start(self.program_counter);
-            self.program_counter = begin - 1; // need to jump before Op::JumpForward
+            self.check_cycle(begin)?;
+            self.program_counter = begin; // condition is already known to be non-zero, skip Op::JumpForward
+        } else {
+            self.observer.on_loop_exit(self.program_counter);
+        }
+        Ok(())
+    }
+
+    /// Executes scan loop starting at current instruction at once. Observer still sees
+    /// every step of the loop.
+    fn scan(&mut self, stride: isize) {
+        let begin = self.program_counter;
+        let end = self.program.find_matching_jump_end(begin);
+        let target = self.memory.find_zero(self.memory_pointer, stride);
+
+        let iterations = (target as isize).wrapping_sub(self.memory_pointer as isize) / stride;
+        for _ in 0..iterations {
+            for position in begin + 1..=end {
+                self.observer.on_step(position, self.program.instructions[position]);
+            }
+        }
+
+        self.memory_pointer = target;
+        self.program_counter = end;
+        self.observer.on_loop_exit(end);
+    }
+
+    #[inline]
+    fn balanced_loop(&self, begin: usize) -> Option<BalancedLoop> {
+        self.balanced_loops.get(begin).copied().flatten()
+    }
+
+    /// Records cells touched by the loop starting at `begin` before its next iteration
+    /// and fails if the same cells were already seen since the loop was entered.
+    fn check_cycle(&mut self, begin: usize) -> Result<(), RuntimeError> {
+        if self.loop_detection != LoopDetection::Cycles || self.loop_states.len() >= MAX_CYCLE_STATES {
+            return Ok(());
+        }
+
+        match self.balanced_loop(begin) {
+            Some(body) if body.max_offset - body.min_offset < MAX_CYCLE_CELLS => {
+                let state = (body.min_offset..=body.max_offset)
+                    .map(|offset| self.memory_at((self.memory_pointer as isize + offset) as usize))
+                    .collect();
+
+                if !self.loop_states.insert(state) {
+                    return Err(RuntimeError::InfiniteLoop(self.program.locations[begin]));
+                }
+                Ok(())
+            }
+            _ => Ok(())
         }
     }
 }
@@ -64,26 +278,20 @@ impl<'a, R: Read, W: Write> Interpreter<'a, R, W> {
 
 #[cfg(test)]
 mod test {
-    use crate::interpreter::Interpreter;
-    use crate::brainfuck::{MAX_MEMORY, Program};
+    use crate::interpreter::{Interpreter, LoopDetection};
+    use crate::observer::Profiler;
+    use crate::brainfuck::{Program, Overflow, RuntimeError, Location};
     use std::io::{Stdin, Stdout, Cursor};
 
-    fn make_interpreter(program: &Program) -> Interpreter<Stdin, Stdout> {
-        return Interpreter {
-            program_counter: 0,
-            program: &program,
-            memory_pointer: 0,
-            memory: [0; MAX_MEMORY],
-            input: std::io::stdin(),
-            output: std::io::stdout(),
-        };
+    fn make_interpreter(program: &Program) -> Interpreter<'_, Stdin, Stdout> {
+        Interpreter::new(program, std::io::stdin(), std::io::stdout())
     }
 
     #[test]
     fn increment_memory() {
         let program = Program::from_string("+++");
         let mut vm = make_interpreter(&program);
-        vm.interpret();
+        vm.interpret().unwrap();
 
         assert_eq!(vm.memory_at(0), 3);
         assert_eq!(vm.memory_at(1), 0);
@@ -94,7 +302,7 @@ mod test {
     fn decrement_memory() {
         let program = Program::from_string("+++--");
         let mut vm = make_interpreter(&program);
-        vm.interpret();
+        vm.interpret().unwrap();
 
         assert_eq!(vm.memory_at(0), 1);
         assert_eq!(vm.memory_at(1), 0);
@@ -105,7 +313,7 @@ mod test {
     fn move_ptr() {
         let program = Program::from_string("+++>++>+<-");
         let mut vm = make_interpreter(&program);
-        vm.interpret();
+        vm.interpret().unwrap();
 
         assert_eq!(vm.memory_at(0), 3);
         assert_eq!(vm.memory_at(1), 1);
@@ -116,25 +324,160 @@ mod test {
     fn loops_work() {
         let program = Program::from_string("+>+++[-]");
         let mut vm = make_interpreter(&program);
-        vm.interpret();
+        vm.interpret().unwrap();
 
         assert_eq!(vm.memory_at(0), 1);
         assert_eq!(vm.memory_at(1), 0);
         assert_eq!(vm.memory_at(2), 0);
     }
 
+    #[test]
+    fn saturates_cells() {
+        let program = Program::from_string("->++--");
+        let mut vm = make_interpreter(&program);
+        vm.overflow = Overflow::Saturate;
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.memory_at(0), 0);
+        assert_eq!(vm.memory_at(1), 0);
+    }
+
+    #[test]
+    fn traps_on_underflow() {
+        let program = Program::from_string("+>\n +--");
+        let mut vm = make_interpreter(&program);
+        vm.overflow = Overflow::Trap;
+
+        assert_eq!(vm.interpret(), Err(RuntimeError::Underflow(Location { line: 2, column: 4 })));
+        assert_eq!(vm.memory_at(1), 0);
+    }
+
+    #[test]
+    fn traps_on_overflow() {
+        let program = Program::from_string("+");
+        let mut vm = make_interpreter(&program);
+        vm.memory[0] = 255;
+        vm.overflow = Overflow::Trap;
+
+        assert_eq!(vm.interpret(), Err(RuntimeError::Overflow(Location { line: 1, column: 1 })));
+    }
+
+    #[test]
+    fn detects_loops_which_cannot_change_condition() {
+        for (source, column) in [("+[]", 2), ("+>+<[<>]", 5), ("+\n[>+<]", 1)].iter() {
+            let program = Program::from_string(source);
+            let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+            let line = if source.contains('\n') { 2 } else { 1 };
+
+            assert_eq!(vm.interpret(), Err(RuntimeError::InfiniteLoop(Location { line, column: *column })));
+        }
+
+        let program = Program::from_string("[]>+[>]+[[-]<]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+        vm.interpret().unwrap();
+        assert_eq!(vm.memory_pointer, 0);
+    }
+
+    #[test]
+    fn traps_in_loops_which_cannot_change_condition() {
+        let program = Program::from_string("+[>+<]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+        vm.overflow = Overflow::Trap;
+
+        assert_eq!(vm.interpret(), Err(RuntimeError::Overflow(Location { line: 1, column: 4 })));
+        assert_eq!(vm.memory_at(1), 255);
+
+        let program = Program::from_string("+[<>]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+        vm.overflow = Overflow::Trap;
+
+        assert_eq!(vm.interpret(), Err(RuntimeError::InfiniteLoop(Location { line: 1, column: 2 })));
+    }
+
+    #[test]
+    fn detects_counter_loops_which_never_finish() {
+        let program = Program::from_string("+++[-->+<]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+        assert_eq!(vm.interpret(), Err(RuntimeError::InfiniteLoop(Location { line: 1, column: 4 })));
+
+        let program = Program::from_string("++++[-->+<]+[+-]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+        assert_eq!(vm.interpret(), Err(RuntimeError::InfiniteLoop(Location { line: 1, column: 13 })));
+        assert_eq!(vm.memory_at(1), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot write to output")]
+    fn keeps_running_loops_which_print() {
+        /* runs until the output is full instead of reporting an infinite loop */
+        let program = Program::from_string("+[.]");
+        let mut output = [0u8; 16];
+        let mut vm = Interpreter::new(&program, std::io::empty(), &mut output[..]);
+
+        vm.interpret().unwrap();
+    }
+
+    #[test]
+    fn detects_cycles_in_small_loops() {
+        /* wrapping counter loops are caught before they start, saturating ones once they repeat */
+        let program = Program::from_string("+++[>+<++]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+        vm.loop_detection = LoopDetection::Cycles;
+        vm.overflow = Overflow::Saturate;
+
+        assert_eq!(vm.interpret(), Err(RuntimeError::InfiniteLoop(Location { line: 1, column: 4 })));
+        assert_eq!(vm.memory_at(1), 255);
+
+        let program = Program::from_string("+[+]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink());
+        vm.loop_detection = LoopDetection::Cycles;
+        vm.overflow = Overflow::Saturate;
+
+        assert_eq!(vm.interpret(), Err(RuntimeError::InfiniteLoop(Location { line: 1, column: 2 })));
+    }
+
+    #[test]
+    fn cycle_detection_lets_terminating_loops_finish() {
+        let program = Program::from_string("+[>+++<+]>.");
+        let mut output = Vec::new();
+        let mut vm = Interpreter::new(&program, std::io::empty(), &mut output);
+        vm.loop_detection = LoopDetection::Cycles;
+        vm.interpret().unwrap();
+
+        assert_eq!(output, vec![255u8.wrapping_mul(3)]);
+    }
+
+    #[test]
+    fn scans_for_zero_cell() {
+        let program = Program::from_string(">>[>]+[<<]");
+        let mut vm = make_interpreter(&program);
+        (0..1000).for_each(|t| vm.memory[t] = 1);
+        vm.memory[999] = 0;
+        vm.memory[1] = 0;
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.memory_at(999), 1);
+        assert_eq!(vm.memory_pointer, 1);
+    }
+
+    #[test]
+    fn scan_loops_report_every_step() {
+        let program = Program::from_string("+>+>+<<[>]>>+[<<]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink())
+            .with_observer(Profiler::default())
+            .with_tape(Vec::new());
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.observer.steps, 7 + 1 + 3 * 2 + 3 + 1 + 3);
+        assert_eq!(vm.observer.loops_entered, 2);
+        assert_eq!(vm.memory_pointer, 3);
+    }
+
     #[test]
     fn can_read_input() {
         let program = Program::from_string(",>,>,");
-        let mut vm = Interpreter {
-            program_counter: 0,
-            program: &program,
-            memory_pointer: 0,
-            memory: [0; MAX_MEMORY],
-            input: Cursor::new(b"abc"),
-            output: std::io::stdout(),
-        };
-        vm.interpret();
+        let mut vm = Interpreter::new(&program, Cursor::new(b"abc"), std::io::stdout());
+        vm.interpret().unwrap();
 
         assert_eq!(vm.memory_at(0), b'a');
         assert_eq!(vm.memory_at(1), b'b');
@@ -145,15 +488,8 @@ mod test {
     fn can_write_output() {
         let program = Program::from_string("++++++++[->+++++++<]>.");
         let mut data = Vec::new();
-        let mut vm = Interpreter {
-            program_counter: 0,
-            program: &program,
-            memory_pointer: 0,
-            memory: [0; MAX_MEMORY],
-            input: std::io::stdin(),
-            output: &mut data,
-        };
-        vm.interpret();
+        let mut vm = Interpreter::new(&program, std::io::stdin(), &mut data);
+        vm.interpret().unwrap();
 
         assert_eq!(vm.memory_at(1), b'8');
         assert_eq!(vm.memory_at(2), 0);
@@ -172,15 +508,8 @@ mod test {
 <<<+<->>>>[>+<<<+++++++++<->>>-]<<<<<[>>+<<-]+<[->-<]>[>>.<<<<[+.[-]]>>-]>[>>.<<
 -]>[-]>[-]>>>[>>[<<<<<<<<+>>>>>>>>-]<<-]]>>[-]<<<[-]<<<<<<<<]++++++++++.");
         let mut data = Vec::new();
-        let mut vm = Interpreter {
-            program_counter: 0,
-            program: &pi_program,
-            memory_pointer: 0,
-            memory: [0; MAX_MEMORY],
-            input: std::io::stdin(),
-            output: &mut data,
-        };
-        vm.interpret();
+        let mut vm = Interpreter::new(&pi_program, std::io::stdin(), &mut data);
+        vm.interpret().unwrap();
 
         assert_eq!(data[0], b'3');
         assert_eq!(data[1], b'.');
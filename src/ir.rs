@@ -1,291 +1,1170 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Error, Formatter};
+use bitflags::bitflags;
 
 use crate::{Op, Program};
+use crate::brainfuck::{Location, Overflow};
+use crate::eval::Evaluator;
 
-/// Link (aka. pointer) to next operation in program graph.
-type Link = Option<usize>;
+/// Steps of the program executed at compile time unless configured otherwise.
+pub const DEFAULT_EVAL_BUDGET: usize = 1_000_000;
+/// Nodes a loop with known number of iterations may be unrolled to.
+const MAX_UNROLLED_SIZE: usize = 64;
 
-/// Operations in intermediate representation.
-#[derive(Debug, Copy, Clone)]
+/// Operations in intermediate representation. Cells are addressed by their offset
+/// from the pointer.
+#[derive(Debug, Clone)]
 pub enum IrOp {
-    Noop(Link),
-    Right(Link, u8),
-    Left(Link, u8),
-    Add(Link, u8),
-    Sub(Link, u8),
-    SetIndirect(Link, u8),
-    /* offset, factor */
-    MulCopy(Link, i8, i8),
-    Write(Link),
-    Read(Link),
-    /* next, addr if 0 */
-    JumpIfZero(Link, Link),
-    /* next, addr if not 0 */
-    JumpIfNotZero(Link, Link),
+    /* moves the pointer right (positive) or left */
+    Move(i32),
+    /* offset, value added (subtracted when negative), kept in the range given by
+     * IrCode::cell_delta for the overflow mode */
+    Add(i32, i16),
+    /* offset, value */
+    SetIndirect(i32, u8),
+    /* source offset, target offset, factor (mod 256) */
+    MulCopy(i32, i32, u8),
+    /* offset, step: replaces cell by number of times step must be added to it to reach
+     * zero, traps when it never does */
+    Iterations(i32, u8),
+    Write(i32),
+    /* writes bytes known at compile time */
+    WriteConst(Vec<u8>),
+    Read(i32),
+    /* body repeated while current cell is not zero */
+    Loop(Vec<IrNode>),
+    /* body executed once if current cell is not zero */
+    IfNonZero(Vec<IrNode>),
+    /* moves pointer by stride until current cell is zero */
+    ScanRight(u8),
+    ScanLeft(u8),
 }
 
 impl IrOp {
-    fn next(&self) -> Link {
-        return match self {
-            IrOp::Noop(l) => l,
-            IrOp::Right(l, _) => l,
-            IrOp::Left(l, _) => l,
-            IrOp::Add(l, _) => l,
-            IrOp::Sub(l, _) => l,
-            IrOp::SetIndirect(l, _) => l,
-            IrOp::MulCopy(l, _, _) => l,
-            IrOp::Write(l) => l,
-            IrOp::Read(l) => l,
-            IrOp::JumpIfZero(l, _) => l,
-            IrOp::JumpIfNotZero(l, _) => l,
-        }.clone();
+    /// Returns this op addressing cells `shift` cells further.
+    fn shifted(self, shift: i32) -> IrOp {
+        match self {
+            IrOp::Add(offset, x) => IrOp::Add(offset + shift, x),
+            IrOp::SetIndirect(offset, x) => IrOp::SetIndirect(offset + shift, x),
+            IrOp::MulCopy(source, target, factor) => IrOp::MulCopy(source + shift, target + shift, factor),
+            IrOp::Iterations(offset, step) => IrOp::Iterations(offset + shift, step),
+            IrOp::Write(offset) => IrOp::Write(offset + shift),
+            IrOp::Read(offset) => IrOp::Read(offset + shift),
+            op => op,
+        }
+    }
+}
+
+bitflags! {
+    /// Set of optimizations which rewrote an op.
+    ///
+    /// Unroll and conditional are done by constant propagation and do nothing without
+    /// it, `enable` and `disable` keep them together.
+    #[derive(Default)]
+    pub struct Optimizations: u16 {
+        const CONSECUTIVE = 0b0001;
+        const CLEAR_LOOP = 0b0010;
+        const MULTIPLICATION_LOOP = 0b0100;
+        const OFFSET_ADDRESSING = 0b1000;
+        const SCAN_LOOP = 0b10000;
+        const COUNTER_LOOP = 0b100000;
+        const CONSTANT_PROPAGATION = 0b1000000;
+        const PARTIAL_EVALUATION = 0b10000000;
+        const CONDITIONAL = 0b100000000;
+        const DEAD_STORE = 0b1000000000;
+        const UNROLL = 0b10000000000;
     }
 }
 
-/// Graph representation of program using intermediate representation with IrOps.
+impl Optimizations {
+    /// Every optimization (pass) with its name used on the command line.
+    const NAMES: [(Optimizations, &'static str); 11] = [
+        (Optimizations::CONSECUTIVE, "consecutive"),
+        (Optimizations::CLEAR_LOOP, "clear-loop"),
+        (Optimizations::MULTIPLICATION_LOOP, "multiplication-loop"),
+        (Optimizations::OFFSET_ADDRESSING, "offset-addressing"),
+        (Optimizations::SCAN_LOOP, "scan-loop"),
+        (Optimizations::COUNTER_LOOP, "counter-loop"),
+        (Optimizations::CONSTANT_PROPAGATION, "constant-propagation"),
+        (Optimizations::PARTIAL_EVALUATION, "partial-evaluation"),
+        (Optimizations::CONDITIONAL, "conditional"),
+        (Optimizations::DEAD_STORE, "dead-store"),
+        (Optimizations::UNROLL, "unroll"),
+    ];
+
+    /// Passes (first) which only run as part of another pass (second).
+    const DEPENDENCIES: [(Optimizations, Optimizations); 2] = [
+        (Optimizations::UNROLL, Optimizations::CONSTANT_PROPAGATION),
+        (Optimizations::CONDITIONAL, Optimizations::CONSTANT_PROPAGATION),
+    ];
+
+    /// Adds `passes` and passes they run in.
+    pub fn enable(&mut self, passes: Optimizations) {
+        *self |= passes;
+        for (dependent, dependency) in Optimizations::DEPENDENCIES.iter() {
+            if passes.contains(*dependent) {
+                *self |= *dependency;
+            }
+        }
+    }
+
+    /// Removes `passes` and passes which run in them.
+    pub fn disable(&mut self, passes: Optimizations) {
+        *self -= passes;
+        for (dependent, dependency) in Optimizations::DEPENDENCIES.iter() {
+            if passes.contains(*dependency) {
+                *self -= *dependent;
+            }
+        }
+    }
+
+    /// Human readable names of optimizations in this set.
+    pub fn names(self) -> Vec<&'static str> {
+        Optimizations::NAMES.iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// Optimization with the given name.
+    pub fn from_name(name: &str) -> Option<Optimizations> {
+        Optimizations::NAMES.iter().find(|(_, t)| *t == name).map(|(flag, _)| *flag)
+    }
+
+    /// Optimizations enabled at `level`: 0 disables everything, 1 folds consecutive
+    /// ops and simple loops, 2 adds solving of loops and data-flow passes and 3 also
+    /// evaluates the program at compile time and unrolls loops.
+    pub fn level(level: u8) -> Optimizations {
+        match level {
+            0 => Optimizations::empty(),
+            1 => Optimizations::CONSECUTIVE | Optimizations::OFFSET_ADDRESSING | Optimizations::CLEAR_LOOP
+                | Optimizations::SCAN_LOOP,
+            2 => Optimizations::level(1) | Optimizations::MULTIPLICATION_LOOP | Optimizations::COUNTER_LOOP
+                | Optimizations::CONSTANT_PROPAGATION | Optimizations::CONDITIONAL | Optimizations::DEAD_STORE,
+            _ => Optimizations::all(),
+        }
+    }
+}
+
+/// How much an optimization changed the program.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PassReport {
+    /* number of times ops were rewritten or removed */
+    pub rewrites: usize,
+    /* number of ops (including loop bodies) removed, negative if more were added */
+    pub removed: isize,
+}
+
+/// Provenance of an op: source instructions it was created from and optimizations
+/// that created it.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Origin {
+    pub location: Location,
+    /* first and last (inclusive) instruction index in Program */
+    pub first: usize,
+    pub last: usize,
+    pub optimizations: Optimizations,
+}
+
+impl Origin {
+    /// Origin of op which replaces ops with this and `other` origin.
+    fn merge(self, other: Origin, optimization: Optimizations) -> Origin {
+        Origin {
+            location: self.location,
+            first: self.first.min(other.first),
+            last: self.last.max(other.last),
+            optimizations: self.optimizations | other.optimizations | optimization,
+        }
+    }
+}
+
+/// Op together with its provenance.
+#[derive(Debug, Clone)]
+pub struct IrNode {
+    pub op: IrOp,
+    pub origin: Origin,
+}
+
+/// Values of cells (relative to the pointer) known by constant propagation.
+#[derive(Clone)]
+pub struct KnownCells {
+    /* None marks cell with unknown value */
+    values: BTreeMap<i32, Option<u8>>,
+    /* cells missing in values are zero, tape starts zeroed until the pointer moves by unknown amount */
+    rest_zero: bool,
+}
+
+impl KnownCells {
+    /// Cells of the tape at the start of program, which are all zero.
+    pub fn zeroed() -> Self {
+        KnownCells { values: BTreeMap::new(), rest_zero: true }
+    }
+
+    pub fn get(&self, offset: i32) -> Option<u8> {
+        match self.values.get(&offset) {
+            Some(value) => *value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    pub fn set(&mut self, offset: i32, value: Option<u8>) {
+        self.values.insert(offset, value);
+    }
+
+    /// Pointer moved by `delta` cells.
+    pub fn shift(&mut self, delta: i32) {
+        self.values = std::mem::take(&mut self.values).into_iter()
+            .map(|(offset, value)| (offset - delta, value))
+            .collect();
+    }
+
+    /// Keeps values which are the same in `other`, pointer must be at the same cell.
+    fn join(&mut self, other: &KnownCells) {
+        let offsets: BTreeSet<i32> = self.values.keys().chain(other.values.keys()).copied().collect();
+        self.values = offsets.into_iter()
+            .map(|offset| (offset, Some(self.get(offset)).filter(|t| *t == other.get(offset)).flatten()))
+            .collect();
+        self.rest_zero = self.rest_zero && other.rest_zero;
+    }
+
+    /// Cell with known value nearest to `offset` and its value. Zero cells of untouched
+    /// tape are looked for only to the right, cells left of the pointer may be outside
+    /// of memory.
+    pub fn nearest(&self, offset: i32) -> Option<(i32, u8)> {
+        let untouched = (offset..).find(|t| !self.values.contains_key(t)).filter(|_| self.rest_zero);
+        self.values.iter()
+            .filter_map(|(offset, value)| value.map(|value| (*offset, value)))
+            .chain(untouched.map(|t| (t, 0)))
+            .min_by_key(|(t, _)| (t - offset).abs())
+    }
+
+    /// Pointer moved to unknown cell.
+    pub fn forget(&mut self) {
+        self.values.clear();
+        self.rest_zero = false;
+    }
+}
+
+/// Tree representation of program using intermediate representation with IrOps.
+/// Loops own their bodies.
 pub struct IrCode {
-    pub ops: Vec<IrOp>
+    pub ops: Vec<IrNode>,
+    /* semantics of Add ops, optimizations must preserve it */
+    pub overflow: Overflow,
+    /* number of steps the input-free prefix of program is executed at compile time */
+    pub eval_budget: usize,
+    /* optimizations run by optimize(), conditional needs constant-propagation */
+    pub passes: Optimizations,
+    /* changes made by each optimization */
+    pub report: BTreeMap<Optimizations, PassReport>,
+    /* whether IR is verified after each pass of optimize() */
+    pub verify_passes: bool,
+    /* instructions of brainfuck source and their locations, empty for parsed IR */
+    pub source: Vec<(Op, Location)>,
 }
 
 impl IrCode {
     pub fn new(program: &Program) -> Self {
-        let mut ops: Vec<IrOp> = Vec::new();
+        /* position of `[` and body of each loop being built, outermost is the program */
+        let mut blocks: Vec<(usize, Vec<IrNode>)> = vec![(0, Vec::new())];
+
         for (idx, op) in program.instructions.iter().enumerate() {
-            let is_last = program.instructions.len() - 1 == idx;
-            let next = if is_last { None } else { Some(idx + 1) };
-
-            ops.push(match op {
-                Op::IncrementPtr => IrOp::Right(next, 1),
-                Op::DecrementPtr => IrOp::Left(next, 1),
-                Op::IncrementMemory => IrOp::Add(next, 1),
-                Op::DecrementMemory => IrOp::Sub(next, 1),
-                Op::ReadByte => IrOp::Read(next),
-                Op::WriteByte => IrOp::Write(next),
-                Op::JumpForward => IrOp::JumpIfZero(next, Some(program.find_matching_jump_end(idx) + 1)),
-                Op::JumpBackward => IrOp::JumpIfNotZero(next, Some(program.find_matching_jump_start(idx))),
-            })
+            let mut origin = Origin { location: program.locations[idx], first: idx, last: idx, optimizations: Optimizations::empty() };
+            let op = match op {
+                Op::IncrementPtr => IrOp::Move(1),
+                Op::DecrementPtr => IrOp::Move(-1),
+                Op::IncrementMemory => IrOp::Add(0, 1),
+                Op::DecrementMemory => IrOp::Add(0, -1),
+                Op::ReadByte => IrOp::Read(0),
+                Op::WriteByte => IrOp::Write(0),
+                Op::JumpForward => {
+                    blocks.push((idx, Vec::new()));
+                    continue;
+                }
+                Op::JumpBackward => {
+                    if blocks.len() == 1 { panic!("unbalanced parentheses") }
+                    let (start, body) = blocks.pop().unwrap();
+                    origin.location = program.locations[start];
+                    origin.first = start;
+                    IrOp::Loop(body)
+                }
+            };
+            blocks.last_mut().unwrap().1.push(IrNode { op, origin });
         }
 
-        IrCode { ops }
+        if blocks.len() != 1 { panic!("unbalanced parentheses") }
+        let mut ir_code = IrCode::from_ops(blocks.pop().unwrap().1);
+        ir_code.source = program.instructions.iter().copied().zip(program.locations.iter().copied()).collect();
+        ir_code
     }
 
-    fn find_replacement(&self, current_idx: usize) -> Vec<IrOp> {
-        let current = self.ops.get(current_idx).expect("current not found");
-        let next_idx = match current.next() {
-            Some(t) => t,
-            None => return vec![*current]
-        };
-        let next = self.ops.get(next_idx).expect("next not found");
-        let subsequent_idx = next.next();
+    /// IrCode with default settings made of `ops`.
+    pub fn from_ops(ops: Vec<IrNode>) -> Self {
+        IrCode {
+            ops,
+            overflow: Overflow::Wrap,
+            eval_budget: DEFAULT_EVAL_BUDGET,
+            passes: Optimizations::all(),
+            report: BTreeMap::new(),
+            verify_passes: cfg!(debug_assertions),
+            source: Vec::new(),
+        }
+    }
+
+    /// Number of nodes in `block` including bodies of loops.
+    fn count(block: &[IrNode]) -> usize {
+        block.iter()
+            .map(|node| match &node.op {
+                IrOp::Loop(body) | IrOp::IfNonZero(body) => 1 + IrCode::count(body),
+                _ => 1,
+            })
+            .sum()
+    }
 
-        /* three consecutive ops */
-        if let Some(t) = subsequent_idx {
-            if let Some(t) = IrCode::find_three_consecutive(current, next, self.ops.get(t).expect("subsequent not found")) { return vec![t]; }
+    /// Records that `pass` replaced `before` nodes by `after` nodes.
+    fn record(&mut self, pass: Optimizations, before: usize, after: usize) {
+        let report = self.report.entry(pass).or_default();
+        report.rewrites += 1;
+        report.removed += before as isize - after as isize;
+    }
+
+    /// Nodes replacing a loop of `size` nodes by `ops` created by `pass`.
+    fn rewrite_loop(&mut self, ops: Vec<IrOp>, mut origin: Origin, pass: Optimizations, size: usize) -> Vec<IrNode> {
+        origin.optimizations |= pass;
+        self.record(pass, size, ops.len());
+        let nodes: Vec<IrNode> = ops.into_iter().map(|op| IrNode { op, origin }).collect();
+        self.check(pass, &nodes);
+        nodes
+    }
+
+    /// Replaces loop with `body` by equivalent straight-line ops if possible.
+    fn optimize_loop(&mut self, body: Vec<IrNode>, origin: Origin) -> Vec<IrNode> {
+        let merged = body.iter().fold(origin, |acc, t| acc.merge(t.origin, Optimizations::empty()));
+        let size = 1 + IrCode::count(&body);
+
+        if self.passes.contains(Optimizations::CLEAR_LOOP) {
+            if let Some(op) = IrCode::find_clear_loop(&body, self.overflow) {
+                return self.rewrite_loop(vec![op], merged, Optimizations::CLEAR_LOOP, size);
+            }
         }
 
-        /* two consecutive ops */
-        if let Some(t) = IrCode::find_two_consecutive(current, next) { return vec![t]; }
+        if self.passes.contains(Optimizations::SCAN_LOOP) {
+            if let Some(op) = IrCode::find_scan_loop(&body) {
+                return self.rewrite_loop(vec![op], merged, Optimizations::SCAN_LOOP, size);
+            }
+        }
 
-        /* multiplication loop */
-        if let IrOp::JumpIfZero(_, _) = current {
-            if let Some(t) = self.find_multiplication_loop(current) {
-                return t;
+        /* multiplication loop (MulCopy always wraps) */
+        if self.overflow == Overflow::Wrap {
+            if self.passes.contains(Optimizations::MULTIPLICATION_LOOP) {
+                if let Some(ops) = IrCode::find_multiplication_loop(&body) {
+                    return self.rewrite_loop(ops, merged, Optimizations::MULTIPLICATION_LOOP, size);
+                }
+            }
+
+            if self.passes.contains(Optimizations::COUNTER_LOOP) {
+                if let Some(ops) = IrCode::find_counter_loop(&body) {
+                    return self.rewrite_loop(ops, merged, Optimizations::COUNTER_LOOP, size);
+                }
             }
         }
 
-        /* nothing to optimize */
-        vec![*current]
+        vec![IrNode { op: IrOp::Loop(body), origin: merged }]
     }
 
-    fn find_three_consecutive(current: &IrOp, next: &IrOp, subsequent: &IrOp) -> Option<IrOp> {
-        return match (current, next, subsequent) {
-            (IrOp::JumpIfZero(_, _), IrOp::Sub(_, 1), IrOp::JumpIfNotZero(far, _)) => Some(IrOp::SetIndirect(*far, 0)),
-            (IrOp::JumpIfZero(_, _), IrOp::Add(_, 1), IrOp::JumpIfNotZero(far, _)) => Some(IrOp::SetIndirect(*far, 0)),
+    fn find_clear_loop(body: &[IrNode], overflow: Overflow) -> Option<IrOp> {
+        match body {
+            [IrNode { op: IrOp::Add(0, -1), .. }] => Some(IrOp::SetIndirect(0, 0)),
+            /* only wrapping [+] reaches zero */
+            [IrNode { op: IrOp::Add(0, 1), .. }] if overflow == Overflow::Wrap => Some(IrOp::SetIndirect(0, 0)),
             _ => None,
-        };
+        }
     }
 
-    fn find_two_consecutive(current: &IrOp, next: &IrOp) -> Option<IrOp> {
-        return match (current, next) {
-            (IrOp::Add(_, x), IrOp::Add(far, y)) => Some(IrOp::Add(*far, *x + *y)),
-            (IrOp::Sub(_, x), IrOp::Sub(far, y)) => Some(IrOp::Sub(*far, *x + *y)),
-            (IrOp::Sub(_, x), IrOp::Add(far, y)) => {
-                let result = *y as i8 - *x as i8;
-                Some(if result > 0 { IrOp::Add(*far, result as u8) } else { IrOp::Sub(*far, -result as u8) })
-            }
-            (IrOp::Add(_, x), IrOp::Sub(far, y)) => {
-                let result = *x as i8 - *y as i8;
-                Some(if result > 0 { IrOp::Add(*far, result as u8) } else { IrOp::Sub(*far, -result as u8) })
-            }
+    fn find_scan_loop(body: &[IrNode]) -> Option<IrOp> {
+        match body {
+            [IrNode { op: IrOp::Move(x @ 1..=255), .. }] => Some(IrOp::ScanRight(*x as u8)),
+            [IrNode { op: IrOp::Move(x @ -255..=-1), .. }] => Some(IrOp::ScanLeft(x.unsigned_abs() as u8)),
+            _ => None,
+        }
+    }
 
-            (IrOp::Right(_, x), IrOp::Right(far, y)) => Some(IrOp::Right(*far, *x + *y)),
-            (IrOp::Left(_, x), IrOp::Left(far, y)) => Some(IrOp::Left(*far, *x + *y)),
-            (IrOp::Right(_, x), IrOp::Left(far, y)) => {
-                let result = *x as i8 - *y as i8;
-                Some(if result > 0 { IrOp::Right(*far, result as u8) } else { IrOp::Left(*far, -result as u8) })
-            }
-            (IrOp::Left(_, x), IrOp::Right(far, y)) => {
-                let result = *y as i8 - *x as i8;
-                Some(if result > 0 { IrOp::Right(*far, result as u8) } else { IrOp::Left(*far, -result as u8) })
-            }
+    /// Returns op replacing `current` followed by `next`. Replacement `None` means
+    /// that both ops can be removed.
+    fn find_two_consecutive(current: &IrOp, next: &IrOp, overflow: Overflow) -> Option<Option<IrOp>> {
+        // with saturating or trapping cells only runs in the same direction can be folded,
+        // result of mixed runs (or trap itself) depends on the value of the cell.
+        let wrapping = overflow == Overflow::Wrap;
+
+        let op = match (current, next) {
+            (IrOp::Add(a, x), IrOp::Add(b, y)) if a == b && (wrapping || x.signum() == y.signum()) =>
+                IrCode::cell_delta(*a, i32::from(*x) + i32::from(*y), overflow),
+
+            (IrOp::Move(x), IrOp::Move(y)) => IrOp::Move(x.checked_add(*y)?),
 
-            (IrOp::SetIndirect(_, c), IrOp::Add(far, x)) => Some(IrOp::SetIndirect(*far, c + x)),
-            (IrOp::SetIndirect(_, c), IrOp::Sub(far, x)) => Some(IrOp::SetIndirect(*far, c.wrapping_sub(*x))),
+            (IrOp::SetIndirect(a, c), IrOp::Add(b, x)) if a == b => IrOp::SetIndirect(*a, overflow.add_signed(*c, *x)?),
 
-            (IrOp::Add(_, _), IrOp::SetIndirect(far, c)) => Some(IrOp::SetIndirect(*far, *c)),
-            (IrOp::Sub(_, _), IrOp::SetIndirect(far, c)) => Some(IrOp::SetIndirect(*far, *c)),
+            /* trapping Add must stay even if its result is overwritten */
+            (IrOp::Add(a, _), IrOp::SetIndirect(b, c)) if a == b && overflow != Overflow::Trap => IrOp::SetIndirect(*a, *c),
 
-            (IrOp::SetIndirect(_, _), IrOp::SetIndirect(far, c)) => Some(IrOp::SetIndirect(*far, *c)),
+            (IrOp::SetIndirect(a, _), IrOp::SetIndirect(b, c)) if a == b => IrOp::SetIndirect(*a, *c),
 
-            (IrOp::SetIndirect(_, 0), IrOp::JumpIfZero(x, y)) => Some(IrOp::JumpIfZero(*x, *y)),
+            /* loop after clear never runs */
+            (IrOp::SetIndirect(0, 0), IrOp::Loop(_)) => IrOp::SetIndirect(0, 0),
+            (IrOp::SetIndirect(0, 0), IrOp::IfNonZero(_)) => IrOp::SetIndirect(0, 0),
+            (IrOp::SetIndirect(0, 0), IrOp::ScanRight(_)) => IrOp::SetIndirect(0, 0),
+            (IrOp::SetIndirect(0, 0), IrOp::ScanLeft(_)) => IrOp::SetIndirect(0, 0),
 
-            (IrOp::Add(_, _), IrOp::Read(far)) => Some(IrOp::Read(*far)),
-            (IrOp::Sub(_, _), IrOp::Read(far)) => Some(IrOp::Read(*far)),
-            (IrOp::SetIndirect(_, _), IrOp::Read(far)) => Some(IrOp::Read(*far)),
+            (IrOp::Add(a, _), IrOp::Read(b)) if a == b && overflow != Overflow::Trap => IrOp::Read(*a),
+            (IrOp::SetIndirect(a, _), IrOp::Read(b)) if a == b => IrOp::Read(*a),
 
-            (_, _) => None,
+            (IrOp::WriteConst(a), IrOp::WriteConst(b)) => IrOp::WriteConst([a.as_slice(), b.as_slice()].concat()),
+
+            (_, _) => return None,
         };
+
+        match op {
+            IrOp::Add(_, 0) | IrOp::Move(0) => Some(None),
+            op => Some(Some(op)),
+        }
+    }
+
+    /// Add op changing cell at `offset` by `delta`. Wrapping deltas are taken mod 256
+    /// into -127..=128, saturating ones clamped to 255 either way and trapping ones to
+    /// 256, which traps from any cell.
+    fn cell_delta(offset: i32, delta: i32, overflow: Overflow) -> IrOp {
+        let delta = match overflow {
+            Overflow::Wrap => match delta.rem_euclid(256) {
+                t if t <= 128 => t,
+                t => t - 256,
+            },
+            Overflow::Saturate => delta.clamp(-255, 255),
+            Overflow::Trap => delta.clamp(-256, 256),
+        };
+        IrOp::Add(offset, delta as i16)
+    }
+
+    /// Returns how much the loop with `body` adds (mod 256) to each cell per iteration if
+    /// it only adds to and subtracts from cells.
+    fn loop_factors(body: &[IrNode]) -> Option<BTreeMap<i32, u8>> {
+        let mut factors: BTreeMap<i32, u8> = BTreeMap::new();
+
+        for node in body {
+            let (offset, delta) = match node.op {
+                IrOp::Add(offset, data) => (offset, data as u8),
+                _ => return None /* None: does not match pattern */
+            };
+
+            let factor = factors.entry(offset).or_insert(0);
+            *factor = factor.wrapping_add(delta);
+        }
+
+        Some(factors)
     }
 
-    fn find_multiplication_loop(&self, current: &IrOp) -> Option<Vec<IrOp>> {
-        if let IrOp::JumpIfZero(_, _) = current {} else { return None; }
+    /// MulCopy ops adding `scale * factor` times the current cell to each cell, followed
+    /// by clearing the current cell.
+    fn mul_copies(factors: BTreeMap<i32, u8>, scale: u8) -> Vec<IrOp> {
+        let mut generated: Vec<IrOp> = factors.into_iter()
+            .map(|(offset, factor)| (offset, factor.wrapping_mul(scale)))
+            .filter(|(_, factor)| *factor != 0)
+            .map(|(offset, factor)| IrOp::MulCopy(0, offset, factor))
+            .collect();
 
-        let mut iter = Iter { ir_code: self, idx: current.next()? }; /* None: next does not exists */
+        generated.push(IrOp::SetIndirect(0, 0));
+        generated
+    }
 
-        // we are matching patterns like: [sub(1), right(1), add(3), right(1), add(7), left(2)]
-        // we will record adds for different offsets by interpreting the code at compile time
-        // if we subtract more than 1, this is not clear-loop and so cannot be multiplication
-        // loop. if offset is at the end different from zero, this is not multiplication loop.
-        // if we see any other instructions, we return none too.
+    fn find_multiplication_loop(body: &[IrNode]) -> Option<Vec<IrOp>> {
+        // we are matching bodies like: [sub(0, 1), add(1, 3), add(2, 7)]
+        // pointer moves were already turned into offsets, so any move left in the body
+        // means that the loop is unbalanced. we record adds (mod 256) for different offsets
+        // and if we do not subtract exactly 1 from the loop cell, this is not clear-loop and so
+        // cannot be multiplication loop. if we see any other instructions, we return none too.
 
-        let mut offset: i8 = 0;
-        let mut factors: [i16; 256] = [0; 256];
-        let far_op: Option<usize>;
+        let mut factors = IrCode::loop_factors(body)?;
 
-        loop {
-            let current = match iter.next() {
+        if factors.remove(&0) != Some(255) { return None; } /* None: we must subtract exactly one from original cell to be clear loop */
+
+        /* all seems good, lets emit instructions */
+        Some(IrCode::mul_copies(factors, 1))
+    }
+
+    fn find_counter_loop(body: &[IrNode]) -> Option<Vec<IrOp>> {
+        // like multiplication loop, but the loop cell changes by any `step`. the loop runs
+        // `n` times where `cell + n * step` is zero (mod 256). odd steps are invertible, so
+        // `n = cell * -step^-1` and factors are just scaled. even steps reach zero only from
+        // cells divisible by their power of two, Iterations checks that and computes `n`.
+
+        let mut factors = IrCode::loop_factors(body)?;
+
+        match factors.remove(&0)? {
+            0 | 255 => None, /* None: loop cell does not change or this is multiplication loop */
+            step if step % 2 == 1 => Some(IrCode::mul_copies(factors, inverse(step).wrapping_neg())),
+            step => {
+                let mut generated = vec![IrOp::Iterations(0, step)];
+                generated.extend(IrCode::mul_copies(factors, 1));
+                Some(generated)
+            }
+        }
+    }
+
+    /// Returns index of op in `block` which `op` may be folded with. Unless cells trap,
+    /// Add and Set ops of other cells are skipped as they do not affect `op`.
+    /// Constant writes skip ops which neither trap nor do input or output.
+    fn fold_candidate(&self, block: &[IrNode], op: &IrOp) -> Option<usize> {
+        let traps = self.overflow == Overflow::Trap;
+        match op {
+            IrOp::Add(target, _) | IrOp::SetIndirect(target, _) if !traps => {
+                block.iter().rposition(|node| match node.op {
+                    IrOp::Add(offset, _) | IrOp::SetIndirect(offset, _) => offset == *target,
+                    _ => true,
+                })
+            }
+            IrOp::WriteConst(_) => block.iter().rposition(|node| match node.op {
+                IrOp::Add(..) => traps,
+                IrOp::SetIndirect(..) | IrOp::MulCopy(..) | IrOp::Move(_) => false,
+                _ => true,
+            }),
+            _ => block.len().checked_sub(1),
+        }
+    }
+
+    /// Appends `node` to `block` folding it with preceding ops.
+    fn push_folded(&mut self, block: &mut Vec<IrNode>, mut node: IrNode) {
+        if !self.passes.contains(Optimizations::CONSECUTIVE) {
+            block.push(node);
+            return;
+        }
+
+        while let Some(idx) = self.fold_candidate(block, &node.op) {
+            let replacement = match IrCode::find_two_consecutive(&block[idx].op, &node.op, self.overflow) {
                 Some(t) => t,
-                None => return None,
+                None => break,
             };
 
-            match current {
-                IrOp::Right(_, data) => offset += *data as i8,
-                IrOp::Left(_, data) => offset -= *data as i8,
-                IrOp::Add(_, data) => {
-                    let idx = offset as usize + 128;
-                    factors[idx] += i16::from(*data)
+            let previous = block.remove(idx);
+            let size = IrCode::count(std::slice::from_ref(&previous)) + IrCode::count(std::slice::from_ref(&node));
+            self.record(Optimizations::CONSECUTIVE, size, replacement.is_some() as usize);
+
+            let origin = previous.origin.merge(node.origin, Optimizations::CONSECUTIVE);
+            match replacement {
+                Some(op) => node = IrNode { op, origin },
+                None => return,
+            }
+            self.check(Optimizations::CONSECUTIVE, std::slice::from_ref(&node));
+        }
+
+        block.push(node);
+    }
+
+    /// Appends `node` to `block` addressing its cells `shift` cells further.
+    fn push_shifted(&mut self, block: &mut Vec<IrNode>, mut node: IrNode, shift: i32) {
+        if shift != 0 {
+            node.op = node.op.shifted(shift);
+            node.origin.optimizations |= Optimizations::OFFSET_ADDRESSING;
+            self.record(Optimizations::OFFSET_ADDRESSING, 1, 1);
+            self.check(Optimizations::OFFSET_ADDRESSING, std::slice::from_ref(&node));
+        }
+        self.push_folded(block, node);
+    }
+
+    /// Appends op moving the pointer by `shift` cells.
+    fn push_move(&mut self, block: &mut Vec<IrNode>, shift: i32, origin: Origin) {
+        if shift != 0 {
+            self.push_folded(block, IrNode { op: IrOp::Move(shift), origin });
+        }
+    }
+
+    /// Optimizes ops of `block`. Pointer moves are deferred and ops address cells by
+    /// offset instead, the pointer is moved once before loops and at the end of block.
+    fn optimize_block(&mut self, block: Vec<IrNode>) -> Vec<IrNode> {
+        let mut optimized = Vec::with_capacity(block.len());
+        /* pointer movement not applied yet, origin and number of ops which made it */
+        let mut moves: Option<(i32, Origin, usize)> = None;
+        let offsets = self.passes.contains(Optimizations::OFFSET_ADDRESSING);
+
+        for node in block {
+            let shift = moves.map(|t| t.0).unwrap_or(0);
+            let delta = match node.op {
+                IrOp::Move(x) if offsets => x,
+                IrOp::Loop(body) => {
+                    let body = self.optimize_block(body);
+                    let replacement = self.optimize_loop(body, node.origin);
+                    if let [IrNode { op: IrOp::Loop(_), .. }]
+                    | [IrNode { op: IrOp::ScanRight(_), .. }]
+                    | [IrNode { op: IrOp::ScanLeft(_), .. }] = replacement.as_slice() {
+                        /* loop tests the cell under pointer */
+                        if let Some(moves) = moves.take() {
+                            self.flush_moves(&mut optimized, moves);
+                        }
+                    }
+
+                    let shift = moves.map(|t| t.0).unwrap_or(0);
+                    replacement.into_iter().for_each(|t| self.push_shifted(&mut optimized, t, shift));
+                    continue;
                 }
-                IrOp::Sub(_, data) => {
-                    let idx = offset as usize + 128;
-                    factors[idx] -= i16::from(*data)
+                /* ops testing the cell under pointer (found in parsed or already optimized IR) */
+                IrOp::IfNonZero(_) | IrOp::ScanRight(_) | IrOp::ScanLeft(_) => {
+                    if let Some(moves) = moves.take() {
+                        self.flush_moves(&mut optimized, moves);
+                    }
+                    let op = match node.op {
+                        IrOp::IfNonZero(body) => IrOp::IfNonZero(self.optimize_block(body)),
+                        op => op,
+                    };
+                    self.push_folded(&mut optimized, IrNode { op, origin: node.origin });
+                    continue;
                 }
-                IrOp::JumpIfNotZero(far, _) => {
-                    far_op = *far;
-                    break;
+                _ => {
+                    self.push_shifted(&mut optimized, node, shift);
+                    continue;
                 }
-                _ => return None /* None: does not match pattern */
+            };
+
+            moves = Some(match moves {
+                Some((shift, origin, count)) => (shift + delta, origin.merge(node.origin, Optimizations::CONSECUTIVE), count + 1),
+                None => (delta, node.origin, 1),
+            });
+        }
+
+        if let Some(moves) = moves {
+            self.flush_moves(&mut optimized, moves);
+        }
+
+        optimized
+    }
+
+    /// Appends pointer `moves` deferred by offset addressing to `block`.
+    fn flush_moves(&mut self, block: &mut Vec<IrNode>, (shift, origin, count): (i32, Origin, usize)) {
+        self.record(Optimizations::OFFSET_ADDRESSING, count, (shift != 0) as usize);
+        self.push_move(block, shift, origin);
+    }
+
+    /// Collects offsets of cells written by `block`. Returns false if the block moves
+    /// the pointer so written cells are not known.
+    pub fn written_cells(block: &[IrNode], written: &mut BTreeSet<i32>) -> bool {
+        block.iter().all(|node| match &node.op {
+            IrOp::Add(offset, _) | IrOp::SetIndirect(offset, _)
+            | IrOp::Iterations(offset, _) | IrOp::Read(offset) | IrOp::MulCopy(_, offset, _) => {
+                written.insert(*offset);
+                true
             }
+            IrOp::Write(_) | IrOp::WriteConst(_) => true,
+            IrOp::Loop(body) | IrOp::IfNonZero(body) => IrCode::written_cells(body, written),
+            IrOp::Move(_) | IrOp::ScanRight(_) | IrOp::ScanLeft(_) => false,
+        })
+    }
+
+    /// Appends op setting cell at `offset` to `value` unless it already has that value.
+    fn push_set(&mut self, block: &mut Vec<IrNode>, known: &mut KnownCells, offset: i32, value: u8, mut origin: Origin) {
+        if known.get(offset) != Some(value) {
+            known.set(offset, Some(value));
+            origin.optimizations |= Optimizations::CONSTANT_PROPAGATION;
+            self.record(Optimizations::CONSTANT_PROPAGATION, 1, 1);
+            let node = IrNode { op: IrOp::SetIndirect(offset, value), origin };
+            self.check(Optimizations::CONSTANT_PROPAGATION, std::slice::from_ref(&node));
+            self.push_folded(block, node);
+        } else {
+            self.record(Optimizations::CONSTANT_PROPAGATION, 1, 0);
         }
+    }
 
-        if factors[128] != -1 { return None; } /* None: we must subtract exactly one from original cell to be clear loop */
-        if offset != 0 { return None; } /* None: lefts/rights unbalanced - would not be clear loop */
+    /// Updates values `known` before IfNonZero with `body` to values after it. `taken`
+    /// are values after the body was executed.
+    pub fn join_conditional(known: &mut KnownCells, taken: &KnownCells, body: &[IrNode]) {
+        /* body is skipped only if the cell is zero */
+        known.set(0, Some(0));
+        if IrCode::written_cells(body, &mut BTreeSet::new()) {
+            known.join(taken);
+        } else {
+            known.forget();
+            known.set(0, taken.get(0).filter(|t| *t == 0));
+        }
+    }
 
-        /* all seems good, lets emit instructions */
-        let mut op_idx = self.ops.len();
-        let mut generated: Vec<IrOp> = factors.iter().enumerate()
-            .filter(|(offset, factor)| *offset != 128 && **factor != 0)
-            .map(|(idx, factor)| {
-                let offset = idx as i16 - 128;
-                let r = IrOp::MulCopy(Some(op_idx), offset as i8, *factor as i8);
-                op_idx += 1;
-                r
-            })
+    /// Appends IfNonZero with `body` to `block`, or just the body when the cell is known
+    /// not to be zero.
+    fn push_conditional(&mut self, block: &mut Vec<IrNode>, known: &mut KnownCells, body: Vec<IrNode>, origin: Origin) {
+        let mut taken = known.clone();
+        let body = self.propagate_block(body, &mut taken);
+
+        if known.get(0).is_some_and(|t| t != 0) {
+            self.record(Optimizations::CONDITIONAL, 1, 0);
+            *known = taken;
+            body.into_iter().for_each(|node| self.push_folded(block, node));
+        } else {
+            IrCode::join_conditional(known, &taken, &body);
+            self.push_folded(block, IrNode { op: IrOp::IfNonZero(body), origin });
+        }
+    }
+
+    /// Number of iterations of loop with `body` entered with `cell` in its loop cell. The
+    /// body must keep the pointer in place and change the cell only by its own adds and
+    /// sets, `None` when it runs more than `limit` times or traps.
+    fn trip_count(&self, body: &[IrNode], mut cell: u8, limit: usize) -> Option<usize> {
+        let mut written = BTreeSet::new();
+        if !IrCode::written_cells(body, &mut written) {
+            return None;
+        }
+
+        let mut changes = Vec::new();
+        for node in body {
+            match node.op {
+                IrOp::Add(0, _) | IrOp::SetIndirect(0, _) => changes.push(&node.op),
+                _ => {
+                    let mut written = BTreeSet::new();
+                    IrCode::written_cells(std::slice::from_ref(node), &mut written);
+                    if written.contains(&0) { return None; }
+                }
+            }
+        }
+
+        let mut count = 0;
+        while cell != 0 {
+            if count == limit { return None; }
+            for change in changes.iter() {
+                cell = match change {
+                    IrOp::Add(_, x) => self.overflow.add_signed(cell, *x)?,
+                    IrOp::SetIndirect(_, x) => *x,
+                    _ => unreachable!("only changes of the loop cell are collected"),
+                };
+            }
+            count += 1;
+        }
+        Some(count)
+    }
+
+    /// Copies of loop `body` for every iteration if the loop cell is `known` and the
+    /// unrolled loop is small.
+    fn unroll(&mut self, body: &[IrNode], known: &KnownCells) -> Option<Vec<IrNode>> {
+        if !self.passes.contains(Optimizations::UNROLL) {
+            return None;
+        }
+        let size = IrCode::count(body);
+        let count = self.trip_count(body, known.get(0)?, MAX_UNROLLED_SIZE / size.max(1))?;
+
+        self.record(Optimizations::UNROLL, 1 + size, count * size);
+        let unrolled: Vec<IrNode> = (0..count).flat_map(|_| body.iter().cloned())
+            .map(|node| IrNode { origin: Origin { optimizations: node.origin.optimizations | Optimizations::UNROLL, ..node.origin }, ..node })
             .collect();
+        self.check(Optimizations::UNROLL, &unrolled);
+        Some(unrolled)
+    }
+
+    /// Replaces ops of `block` using cells with `known` values. Loops (the jump if zero at
+    /// their start) whose cell is known to be zero are removed. Cells written in a loop
+    /// which keeps the pointer in place become unknown for the whole loop, any other
+    /// loop forgets everything.
+    fn propagate_block(&mut self, block: Vec<IrNode>, known: &mut KnownCells) -> Vec<IrNode> {
+        let mut propagated = Vec::with_capacity(block.len());
+
+        for node in block {
+            let origin = node.origin;
+            match node.op {
+                IrOp::Move(x) => known.shift(x),
+                IrOp::Add(offset, x) => match known.get(offset).and_then(|c| self.overflow.add_signed(c, x)) {
+                    Some(value) => { self.push_set(&mut propagated, known, offset, value, origin); continue; }
+                    None => known.set(offset, None),
+                },
+                IrOp::SetIndirect(offset, value) => {
+                    if known.get(offset) == Some(value) {
+                        self.record(Optimizations::CONSTANT_PROPAGATION, 1, 0);
+                        continue;
+                    }
+                    known.set(offset, Some(value));
+                }
+                IrOp::MulCopy(source, target, factor) => match (known.get(source), known.get(target)) {
+                    (Some(0), _) => {
+                        self.record(Optimizations::CONSTANT_PROPAGATION, 1, 0);
+                        continue;
+                    }
+                    (Some(x), Some(y)) => {
+                        self.push_set(&mut propagated, known, target, y.wrapping_add(x.wrapping_mul(factor)), origin);
+                        continue;
+                    }
+                    (Some(x), None) => {
+                        let op = IrCode::cell_delta(target, i32::from(x.wrapping_mul(factor)), self.overflow);
+                        let origin = Origin { optimizations: origin.optimizations | Optimizations::CONSTANT_PROPAGATION, ..origin };
+                        self.record(Optimizations::CONSTANT_PROPAGATION, 1, 1);
+                        let node = IrNode { op, origin };
+                        self.check(Optimizations::CONSTANT_PROPAGATION, std::slice::from_ref(&node));
+                        self.push_folded(&mut propagated, node);
+                        continue;
+                    }
+                    (None, _) => known.set(target, None),
+                },
+                IrOp::Iterations(offset, step) => match known.get(offset).and_then(|c| iterations(c, step)) {
+                    Some(value) => { self.push_set(&mut propagated, known, offset, value, origin); continue; }
+                    None => known.set(offset, None),
+                },
+                IrOp::Read(offset) => known.set(offset, None),
+                IrOp::Write(offset) => if let Some(value) = known.get(offset) {
+                    let origin = Origin { optimizations: origin.optimizations | Optimizations::CONSTANT_PROPAGATION, ..origin };
+                    self.record(Optimizations::CONSTANT_PROPAGATION, 1, 1);
+                    let node = IrNode { op: IrOp::WriteConst(vec![value]), origin };
+                    self.check(Optimizations::CONSTANT_PROPAGATION, std::slice::from_ref(&node));
+                    self.push_folded(&mut propagated, node);
+                    continue;
+                },
+                IrOp::WriteConst(_) => {}
+                IrOp::ScanRight(_) | IrOp::ScanLeft(_) => {
+                    if known.get(0) == Some(0) {
+                        self.record(Optimizations::CONSTANT_PROPAGATION, 1, 0);
+                        continue;
+                    }
+                    known.forget();
+                    known.set(0, Some(0));
+                }
+                IrOp::Loop(body) => {
+                    if known.get(0) == Some(0) {
+                        self.record(Optimizations::CONSTANT_PROPAGATION, 1 + IrCode::count(&body), 0);
+                        continue;
+                    }
+
+                    if let Some(unrolled) = self.unroll(&body, known) {
+                        let unrolled = self.propagate_block(unrolled, known);
+                        unrolled.into_iter().for_each(|node| self.push_folded(&mut propagated, node));
+                        continue;
+                    }
+
+                    /* values at the start of every iteration */
+                    let mut entry = known.clone();
+                    let mut written = BTreeSet::new();
+                    if IrCode::written_cells(&body, &mut written) {
+                        written.into_iter().for_each(|offset| entry.set(offset, None));
+                    } else {
+                        entry.forget();
+                    }
+
+                    let mut iteration = entry.clone();
+                    let body = self.propagate_block(body, &mut iteration);
+
+                    /* body which always clears the loop cell runs at most once and can use
+                     * values known before the loop */
+                    if iteration.get(0) == Some(0) && self.passes.contains(Optimizations::CONDITIONAL) {
+                        let origin = Origin { optimizations: origin.optimizations | Optimizations::CONDITIONAL, ..origin };
+                        self.record(Optimizations::CONDITIONAL, 1, 1);
+                        self.check(Optimizations::CONDITIONAL, &body);
+                        self.push_conditional(&mut propagated, known, body, origin);
+                        continue;
+                    }
+
+                    self.push_folded(&mut propagated, IrNode { op: IrOp::Loop(body), origin });
+                    *known = entry;
+                    known.set(0, Some(0));
+                    continue;
+                }
+                IrOp::IfNonZero(body) => {
+                    if known.get(0) == Some(0) {
+                        self.record(Optimizations::CONSTANT_PROPAGATION, 1 + IrCode::count(&body), 0);
+                        continue;
+                    }
+                    self.push_conditional(&mut propagated, known, body, origin);
+                    continue;
+                }
+            }
 
-        generated.push(IrOp::SetIndirect(far_op, 0));
+            self.push_folded(&mut propagated, node);
+        }
 
-        Some(generated)
+        propagated
     }
 
-    fn optimize_program_once(&mut self) -> usize {
-        let mut idx = 0;
-        let mut len = 0;
+    /// Folds consecutive ops, replaces loops by simpler ops and addresses cells by offset.
+    fn fold(&mut self) {
+        let ops = std::mem::take(&mut self.ops);
+        self.ops = self.optimize_block(ops);
+    }
 
-        loop {
-            if idx == std::usize::MAX { return len; }
+    /// Replaces ops using cells with values known at compile time.
+    fn propagate_constants(&mut self) {
+        let ops = std::mem::take(&mut self.ops);
+        /* program starts with zeroed tape */
+        self.ops = self.propagate_block(ops, &mut KnownCells::zeroed());
+    }
 
-            let replacement = self.find_replacement(idx);
-            let first = replacement.first().expect("find_replacement returned empty vector");
-            let last = replacement.last().unwrap();
+    /// Collects offsets of cells read or written by `block`. Returns false if the block
+    /// moves the pointer.
+    pub fn touched_cells(block: &[IrNode], touched: &mut BTreeSet<i32>) -> bool {
+        block.iter().all(|node| match &node.op {
+            IrOp::MulCopy(source, _, _) => {
+                touched.insert(*source);
+                IrCode::written_cells(std::slice::from_ref(node), touched)
+            }
+            IrOp::Write(offset) => {
+                touched.insert(*offset);
+                true
+            }
+            IrOp::Loop(body) | IrOp::IfNonZero(body) => {
+                touched.insert(0);
+                IrCode::touched_cells(body, touched)
+            }
+            _ => IrCode::written_cells(std::slice::from_ref(node), touched),
+        })
+    }
 
-            // push new instructions to ops array (links should be set-up by find_replacement)
-            replacement.iter()
-                .skip(1)
-                .for_each(|x| self.ops.push(*x));
+    /// Removes ops of `block` whose result is overwritten before it is read. Cells are
+    /// considered read at the end of each block, so only stores overwritten in the same
+    /// block are removed. Loops which keep the pointer in place do not hide cells they
+    /// do not touch.
+    fn eliminate_dead_stores_block(&mut self, block: Vec<IrNode>) -> Vec<IrNode> {
+        /* offsets of cells overwritten later before being read, relative to the pointer */
+        let mut dead: BTreeSet<i32> = BTreeSet::new();
+        let mut kept = Vec::with_capacity(block.len());
+
+        for mut node in block.into_iter().rev() {
+            match &mut node.op {
+                IrOp::Move(x) => dead = dead.into_iter().map(|t| t + *x).collect(),
+                IrOp::SetIndirect(offset, _) => {
+                    if !dead.insert(*offset) {
+                        self.record(Optimizations::DEAD_STORE, 1, 0);
+                        continue;
+                    }
+                }
+                /* trapping op must stay even if its result is overwritten, the whole
+                 * tape is left as it is when it traps */
+                IrOp::Add(_, _) if self.overflow == Overflow::Trap => dead.clear(),
+                IrOp::Add(offset, _) => {
+                    if dead.contains(offset) {
+                        self.record(Optimizations::DEAD_STORE, 1, 0);
+                        continue;
+                    }
+                    dead.remove(offset);
+                }
+                IrOp::MulCopy(source, target, _) => {
+                    if dead.contains(target) {
+                        self.record(Optimizations::DEAD_STORE, 1, 0);
+                        continue;
+                    }
+                    dead.remove(source);
+                    dead.remove(target);
+                }
+                /* iterations with even step trap on odd cells */
+                IrOp::Iterations(_, step) if *step % 2 == 0 => dead.clear(),
+                IrOp::Iterations(offset, _) | IrOp::Write(offset) => { dead.remove(offset); }
+                /* input must be consumed even if the cell is overwritten */
+                IrOp::Read(offset) => { dead.insert(*offset); }
+                IrOp::WriteConst(_) => {}
+                IrOp::ScanRight(_) | IrOp::ScanLeft(_) => dead.clear(),
+                IrOp::Loop(body) | IrOp::IfNonZero(body) => {
+                    let mut touched = BTreeSet::new();
+                    let overflow = self.overflow;
+                    let traps = IrCode::iter_block(body).any(|t| match t.op {
+                        IrOp::Add(..) => overflow == Overflow::Trap,
+                        IrOp::Iterations(_, step) => step % 2 == 0,
+                        _ => false,
+                    });
+                    if IrCode::touched_cells(body, &mut touched) && !traps {
+                        dead = dead.difference(&touched).copied().collect();
+                        dead.remove(&0);
+                    } else {
+                        dead.clear();
+                    }
+                    *body = self.eliminate_dead_stores_block(std::mem::take(body));
+                }
+            }
+            kept.push(node);
+        }
 
-            let next_idx = match last.next() {
-                Some(t) => t,
-                None => std::usize::MAX,
+        kept.reverse();
+        kept
+    }
+
+    /// Removes stores to cells which are overwritten before they are read.
+    fn eliminate_dead_stores(&mut self) {
+        let ops = std::mem::take(&mut self.ops);
+        self.ops = self.eliminate_dead_stores_block(ops);
+    }
+
+    /// Executes ops at the start of program until the first one which reads input (or
+    /// runs out of budget) at compile time. Executed ops are replaced by their output and
+    /// initialisation of the tape.
+    fn evaluate_prefix(&mut self) {
+        let mut evaluator = Evaluator::new(self.overflow, self.eval_budget);
+        let mut evaluated = 0;
+
+        for node in self.ops.iter() {
+            /* loop may halt half way, its state must not be used */
+            let snapshot = match node.op {
+                IrOp::Loop(_) | IrOp::IfNonZero(_) => Some(evaluator.clone()),
+                _ => None,
             };
-            self.ops[idx] = *first;
-            idx = next_idx;
-            len += 1;
+
+            if evaluator.execute(node).is_err() {
+                if let Some(snapshot) = snapshot {
+                    evaluator = snapshot;
+                }
+                break;
+            }
+            evaluated += 1;
+        }
+
+        if evaluated == 0 { return; }
+
+        let rest = self.ops.split_off(evaluated);
+        let size = IrCode::count(&self.ops);
+        let origin = self.ops.iter().skip(1)
+            .fold(self.ops[0].origin, |acc, t| acc.merge(t.origin, Optimizations::empty()));
+        let origin = Origin { optimizations: origin.optimizations | Optimizations::PARTIAL_EVALUATION, ..origin };
+
+        let mut prefix = Vec::new();
+        if !evaluator.output.is_empty() {
+            prefix.push(IrNode { op: IrOp::WriteConst(evaluator.output), origin });
+        }
+        for (address, value) in evaluator.memory.iter().enumerate().filter(|(_, value)| **value != 0) {
+            prefix.push(IrNode { op: IrOp::SetIndirect(address as i32, *value), origin });
+        }
+        self.push_move(&mut prefix, evaluator.pointer as i32, origin);
+        self.record(Optimizations::PARTIAL_EVALUATION, size, prefix.len());
+        self.check(Optimizations::PARTIAL_EVALUATION, &prefix);
+
+        prefix.extend(rest);
+        self.ops = prefix;
+    }
+
+    /// Panics when `block` rewritten by `pass` is not valid and passes are verified.
+    fn check(&self, pass: Optimizations, block: &[IrNode]) {
+        if !self.verify_passes { return; }
+        if let Err(e) = self.verify_block(block) {
+            panic!("invalid IR after {}: {}", pass.names().join(", "), e);
         }
     }
 
+    /// Runs enabled optimization passes. Ops are checked after every rewrite and the
+    /// whole program after every stage when passes are verified.
     pub fn optimize(&mut self) {
-        let mut old = self.optimize_program_once();
+        if self.verify_passes {
+            if let Err(e) = self.verify() {
+                panic!("invalid IR before optimization: {}", e);
+            }
+        }
 
-        loop {
-            let new = self.optimize_program_once();
-            if new >= old { break; }
-            old = new;
+        self.fold();
+        let folding = Optimizations::CONSECUTIVE | Optimizations::CLEAR_LOOP | Optimizations::MULTIPLICATION_LOOP
+            | Optimizations::OFFSET_ADDRESSING | Optimizations::SCAN_LOOP | Optimizations::COUNTER_LOOP;
+        self.check(self.passes & folding, &self.ops);
+        if self.passes.contains(Optimizations::PARTIAL_EVALUATION) {
+            self.evaluate_prefix();
+            self.check(Optimizations::PARTIAL_EVALUATION, &self.ops);
+        }
+        if self.passes.contains(Optimizations::CONSTANT_PROPAGATION) {
+            self.propagate_constants();
+            self.check(Optimizations::CONSTANT_PROPAGATION, &self.ops);
+        }
+        if self.passes.contains(Optimizations::DEAD_STORE) {
+            self.eliminate_dead_stores();
+            self.check(Optimizations::DEAD_STORE, &self.ops);
         }
     }
 
-    pub fn iter(&self) -> Iter {
-        Iter { ir_code: &self, idx: 0 }
+    /// Locations of `+` (or `-`) instructions which Add `node` was folded from, in the
+    /// order they run. `None` when it was made of other instructions too.
+    pub fn run_locations(&self, node: &IrNode) -> Option<Vec<Location>> {
+        let delta = match node.op {
+            IrOp::Add(_, x) => x,
+            _ => return None,
+        };
+        let mut locations = Vec::new();
+        for (op, location) in self.source.get(node.origin.first..=node.origin.last)? {
+            match op {
+                Op::IncrementPtr | Op::DecrementPtr => {}
+                Op::IncrementMemory if delta > 0 => locations.push(*location),
+                Op::DecrementMemory if delta < 0 => locations.push(*location),
+                _ => return None,
+            }
+        }
+        /* longer trapping runs are clamped, they always trap within the first 256 */
+        if delta.unsigned_abs() == 256 {
+            locations.truncate(256);
+        }
+        Some(locations).filter(|t| t.len() == delta.unsigned_abs() as usize)
+    }
+
+    /// Iterates all nodes in program order, loop node is followed by nodes of its body.
+    pub fn iter(&self) -> Iter<'_> {
+        IrCode::iter_block(&self.ops)
     }
 
-    // O(n)
+    /// Iterates over nodes of `block` and all nested loop bodies.
+    pub fn iter_block(block: &[IrNode]) -> Iter<'_> {
+        Iter { stack: vec![block.iter()] }
+    }
+
+    /// Number of nodes (including loops) in the program.
     pub fn len(&self) -> usize {
         self.iter().count()
     }
 }
 
-pub struct Iter<'a> {
-    ir_code: &'a IrCode,
-    idx: usize,
+/// Multiplicative inverse of odd `value` mod 256.
+pub fn inverse(value: u8) -> u8 {
+    debug_assert!(value % 2 == 1, "only odd numbers are invertible");
+    (1..=255).find(|t: &u8| t.wrapping_mul(value) == 1).unwrap()
 }
 
-impl<'a> Iterator for Iter<'a> {
-    type Item = &'a IrOp;
+/// Number of times `step` must be added to `cell` to make it zero (mod 256), `None`
+/// when it never becomes zero.
+pub fn iterations(cell: u8, step: u8) -> Option<u8> {
+    if step == 0 {
+        return if cell == 0 { Some(0) } else { None };
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.ir_code.ops
-            .get(self.idx)
-            .map(|t| {
-                self.idx = t.next().unwrap_or(std::usize::MAX); // proceed or point to invalid idx
-                t
-            })
+    let shift = step.trailing_zeros();
+    if cell.trailing_zeros() < shift {
+        return None;
     }
+    Some((cell >> shift).wrapping_mul(inverse(step >> shift).wrapping_neg()) & (0xFF >> shift))
 }
 
-impl Debug for IrCode {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        let mut current = self.ops.get(0);
+pub struct Iter<'a> {
+    /* iterators over the program and bodies of loops being visited */
+    stack: Vec<std::slice::Iter<'a, IrNode>>,
+}
 
-        f.write_str("IrCode {\n")?;
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a IrNode;
 
+    fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if current.is_none() { break; }
-
-            let next = current.unwrap().next();
+            match self.stack.last_mut()?.next() {
+                Some(node) => {
+                    if let IrOp::Loop(body) | IrOp::IfNonZero(body) = &node.op {
+                        self.stack.push(body.iter());
+                    }
+                    return Some(node);
+                }
+                None => { self.stack.pop(); }
+            }
+        }
+    }
+}
 
-            f.write_fmt(format_args!("\t{:?},\n", current))?;
-            current = next.and_then(|x| self.ops.get(x));
+fn fmt_block(f: &mut Formatter, block: &[IrNode], depth: usize) -> Result<(), Error> {
+    for node in block {
+        let indent = "\t".repeat(depth);
+        match &node.op {
+            IrOp::Loop(body) => {
+                writeln!(f, "{}Loop {{", indent)?;
+                fmt_block(f, body, depth + 1)?;
+                writeln!(f, "{}}},", indent)?;
+            }
+            IrOp::IfNonZero(body) => {
+                writeln!(f, "{}IfNonZero {{", indent)?;
+                fmt_block(f, body, depth + 1)?;
+                writeln!(f, "{}}},", indent)?;
+            }
+            op => writeln!(f, "{}{:?},", indent, op)?,
         }
+    }
+    Ok(())
+}
 
+impl Debug for IrCode {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("IrCode {\n")?;
+        fmt_block(f, &self.ops, 1)?;
         f.write_str("}\n")?;
         Ok(())
     }
@@ -295,36 +1174,54 @@ impl Debug for IrCode {
 mod test {
     use matches::assert_matches;
 
-    use crate::brainfuck::Program;
-    use crate::ir::{IrCode, IrOp};
+    use crate::brainfuck::{Program, Overflow};
+    use crate::eval::Evaluator;
+    use crate::ir::{IrCode, IrOp, Optimizations, PassReport, inverse, iterations};
+    use crate::verify::verify;
 
     #[test]
     fn iter() {
         let ir_code = IrCode::new(&Program::from_string("+-<>.,"));
-        let mut iter = ir_code.iter();
-
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Sub(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Left(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Write(_)));
-        assert_matches!(iter.next(), Some(IrOp::Read(_)));
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(-1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), Some(IrOp::Write(0)));
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
         assert_matches!(iter.next(), None);
     }
 
+    #[test]
+    fn loops_own_their_bodies() {
+        let ir_code = IrCode::new(&Program::from_string("+[>[-]<-]."));
+
+        assert_eq!(ir_code.ops.len(), 3);
+        assert_matches!(&ir_code.ops[1].op, IrOp::Loop(body) if body.len() == 4);
+        assert_eq!((ir_code.ops[1].origin.first, ir_code.ops[1].origin.last), (1, 8));
+        assert_eq!(ir_code.len(), 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "unbalanced parentheses")]
+    fn rejects_unbalanced_loops() {
+        IrCode::new(&Program::from_string("+[>[-]<-"));
+    }
+
     #[test]
     fn len() {
         let mut ir_code = IrCode::new(&Program::from_string("+++>+"));
 
         assert_eq!(ir_code.len(), 5);
-        ir_code.optimize();
+        ir_code.fold();
         assert_eq!(ir_code.len(), 3);
     }
 
     #[test]
     fn can_print_debug() {
         let mut ir_code = IrCode::new(&Program::from_string("++[+++[->++>+++<<]>>>[+]--<<<-]"));
-        ir_code.optimize();
+        ir_code.fold();
 
         println!("{:?}", ir_code);
     }
@@ -332,142 +1229,282 @@ mod test {
     #[test]
     fn optimizes_tail_instructions() {
         let mut ir_code = IrCode::new(&Program::from_string("+++"));
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 3)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 3)));
         assert_matches!(iter.next(), None);
     }
 
     #[test]
     fn optimizes_multiplication_loops() {
         let mut ir_code = IrCode::new(&Program::from_string("++[+++[->++>+++<<]>>>[+]--<<<-]"));
-        ir_code.optimize();
-
-        let mut iter = ir_code.iter();
-
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 2)));
-        assert_matches!(iter.next(), Some(IrOp::JumpIfZero(_, _)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 3)));
-        assert_matches!(iter.next(), Some(IrOp::MulCopy(_, 1, 2)));
-        assert_matches!(iter.next(), Some(IrOp::MulCopy(_, 2, 3)));
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 0)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 3)));
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 254)));
-        assert_matches!(iter.next(), Some(IrOp::Left(_, 3)));
-        assert_matches!(iter.next(), Some(IrOp::Sub(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::JumpIfNotZero(_, _)));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 2)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 3)));
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, 1, 2)));
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, 2, 3)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(3, 254)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 255)));
         assert_matches!(iter.next(), None);
     }
 
     #[test]
     fn multiplication_loop_negative_bug() {
         let mut ir_code = IrCode::new(&Program::from_string("[>----<-]"));
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        assert_matches!(iter.next(), Some(IrOp::MulCopy(_, 1, -4)));
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 0)));
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, 1, 252)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
         assert_matches!(iter.next(), None);
     }
 
+    #[test]
+    fn multiplication_loops_reach_far_cells() {
+        let source = format!("[-{}+{}-{}]", ">".repeat(300), "<".repeat(600), ">".repeat(300));
+        let mut ir_code = IrCode::new(&Program::from_string(&source));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, -300, 255)));
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, 300, 1)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn multiplication_loops_wrap_factors() {
+        /* 200 does not fit i8, 256 additions cancel out */
+        let source = format!("[>{}>{}<<-]", "+".repeat(200), "+".repeat(256));
+        let mut ir_code = IrCode::new(&Program::from_string(&source));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, 1, 200)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), None);
+
+        /* counter changed by 257 is changed by 1 */
+        let source = format!("[>+<{}]", "-".repeat(257));
+        let mut ir_code = IrCode::new(&Program::from_string(&source));
+        ir_code.fold();
+
+        assert_matches!(ir_code.ops[0].op, IrOp::MulCopy(0, 1, 1));
+    }
+
+    #[test]
+    fn optimizes_counter_loops() {
+        let mut ir_code = IrCode::new(&Program::from_string("[+>+<][--->++<]>>[-->+++<]"));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        /* counter 1 runs 255 times */
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, 1, 255)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        /* counter 3 runs once, 3 * 86 = 2 (mod 256) */
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(0, 1, 86)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), Some(IrOp::Iterations(2, 254)));
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(2, 3, 3)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(2, 0)));
+        assert_matches!(iter.next(), Some(IrOp::Move(2)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn keeps_counter_loops_when_cells_saturate() {
+        let mut ir_code = IrCode::new(&Program::from_string("[-->+<]"));
+        ir_code.overflow = Overflow::Saturate;
+        ir_code.fold();
+
+        assert_matches!(ir_code.ops[0].op, IrOp::Loop(_));
+    }
+
+    #[test]
+    fn inverts_odd_numbers() {
+        assert_eq!(inverse(1), 1);
+        assert_eq!(inverse(3), 171);
+        assert_eq!(inverse(255), 255);
+        assert!((1..=255).step_by(2).all(|t: u8| t.wrapping_mul(inverse(t)) == 1));
+    }
 
     #[test]
     fn optimizes_consecutive_adds() {
         let mut ir_code = IrCode::new(&Program::from_string("+++>++"));
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 3)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 2)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 3)));
+        assert_matches!(iter.next(), Some(IrOp::Add(1, 2)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
         assert_matches!(iter.next(), None);
     }
 
     #[test]
     fn optimizes_consecutive_mixed_adds() {
         let mut ir_code = IrCode::new(&Program::from_string("+++-->---++>--+++>++---"));
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
-
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Sub(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Sub(_, 1)));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(1, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(2, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(3, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(3)));
         assert_matches!(iter.next(), None);
     }
 
     #[test]
     fn optimizes_consecutive_mixed_lefts_rights() {
         let mut ir_code = IrCode::new(&Program::from_string(">>><<+<<<>>+<<>>>+>><<<"));
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Left(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Left(_, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(1, 2)));
         assert_matches!(iter.next(), None);
     }
 
     #[test]
     fn optimizes_consecutive_subtractions() {
         let mut ir_code = IrCode::new(&Program::from_string("--->-"));
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        assert_matches!(iter.next(), Some(IrOp::Sub(_, 3)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Sub(_, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -3)));
+        assert_matches!(iter.next(), Some(IrOp::Add(1, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
         assert_matches!(iter.next(), None);
     }
 
     #[test]
     fn optimizes_consecutive_lefts_rights() {
         let mut ir_code = IrCode::new(&Program::from_string(">>+>>>-<<<<+"));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        assert_matches!(iter.next(), Some(IrOp::Add(2, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(5, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(1, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn removes_cancelling_ops() {
+        let mut ir_code = IrCode::new(&Program::from_string("+[>+-<><]+-."));
+        ir_code.fold();
+
+        assert_eq!(ir_code.len(), 3);
+        assert_matches!(&ir_code.ops[1].op, IrOp::Loop(body) if body.is_empty());
+        assert_matches!(ir_code.ops[2].op, IrOp::Write(0));
+    }
+
+    #[test]
+    fn addresses_cells_by_offset() {
+        let mut ir_code = IrCode::new(&Program::from_string(">+>++<<-[>.<,]>>>[<]"));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Add(1, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(2, 2)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::Write(1)));
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::Move(3)));
+        assert_matches!(iter.next(), Some(IrOp::ScanLeft(1)));
+        assert_matches!(iter.next(), None);
+    }
 
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 2)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 3)));
-        assert_matches!(iter.next(), Some(IrOp::Sub(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::Left(_, 4)));
-        assert_matches!(iter.next(), Some(IrOp::Add(_, 1)));
+    #[test]
+    fn folds_long_pointer_moves() {
+        let mut ir_code = IrCode::new(&Program::from_string(&format!("{}+{}-", ">".repeat(300), "<".repeat(600))));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Add(300, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(-300, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(-300)));
         assert_matches!(iter.next(), None);
     }
 
+    #[test]
+    fn folds_long_runs() {
+        let fold = |source: &str, overflow: Overflow, passes: Optimizations| {
+            let mut ir_code = IrCode::new(&Program::from_string(source));
+            ir_code.overflow = overflow;
+            ir_code.passes = passes;
+            ir_code.fold();
+            ir_code.ops.into_iter().map(|t| t.op).collect::<Vec<_>>()
+        };
+        let long = format!("{}{}", "+".repeat(300), "-".repeat(1000));
+
+        assert_matches!(fold(&"+".repeat(1000), Overflow::Wrap, Optimizations::all())[..], [IrOp::Add(0, -24)]);
+        assert_matches!(fold(&long, Overflow::Wrap, Optimizations::all())[..], [IrOp::Add(0, 68)]);
+        assert_matches!(fold(&long, Overflow::Saturate, Optimizations::all())[..], [IrOp::Add(0, 255), IrOp::Add(0, -255)]);
+        assert_matches!(fold(&"+".repeat(300), Overflow::Trap, Optimizations::all())[..], [IrOp::Add(0, 256)]);
+        assert_matches!(fold(&format!("{}{}", ">".repeat(70000), "<".repeat(100000)), Overflow::Wrap, Optimizations::CONSECUTIVE)[..],
+                        [IrOp::Move(-30000)]);
+    }
+
+    #[test]
+    fn runs_long_runs() {
+        let source = format!("{}.{}{}.{}{}.", "+".repeat(1000), ">".repeat(20000), "-".repeat(300),
+                             "<".repeat(19999), "+".repeat(100));
+        for overflow in &[Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+            for passes in &[Optimizations::CONSECUTIVE, Optimizations::all()] {
+                assert!(verify(&Program::from_string(&source), b"", *overflow, *passes).is_empty());
+            }
+        }
+    }
+
     #[test]
     fn optimizes_clear_loops() {
         let mut ir_code = IrCode::new(&Program::from_string("[-]>[+]>"));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 0)));
+        assert_matches!(iter.next(), Some(IrOp::Move(2)));
+        assert_matches!(iter.next(), None);
+    }
 
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 0)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 0)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
+    #[test]
+    fn optimizes_scan_loops() {
+        let mut ir_code = IrCode::new(&Program::from_string("[>]+[<<]>[>>>>]"));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::ScanRight(1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::ScanLeft(2)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), Some(IrOp::ScanRight(4)));
+        assert_matches!(iter.next(), None);
+
+        let mut ir_code = IrCode::new(&Program::from_string("[-][>]"));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
         assert_matches!(iter.next(), None);
     }
 
     #[test]
     fn optimizes_adds_following_preceding_clear_loops() {
         let mut ir_code = IrCode::new(&Program::from_string("+[-]+++++>-[+]----"));
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
-
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 5)));
-        assert_matches!(iter.next(), Some(IrOp::Right(_, 1)));
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 252)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 5)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 252)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
         assert_matches!(iter.next(), None);
     }
 
@@ -475,12 +1512,493 @@ mod test {
     fn optimizes_consecutive_sets() {
         let mut ir_code = IrCode::new(&Program::from_string("+[-]+++++-[+]----"));
 
-        ir_code.optimize();
-        let mut iter = ir_code.iter();
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
 
         assert_eq!(ir_code.len(), 1);
 
-        assert_matches!(iter.next(), Some(IrOp::SetIndirect(_, 252)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 252)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn saturating_folds_only_same_direction() {
+        let mut ir_code = IrCode::new(&Program::from_string("+++--[-]+++[+]>>>+++--[->+<]"));
+        ir_code.overflow = Overflow::Saturate;
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 3)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(3, 3)));
+        assert_matches!(iter.next(), Some(IrOp::Add(3, -2)));
+        assert_matches!(iter.next(), Some(IrOp::Move(3)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(1, 1)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn trapping_keeps_trapping_ops() {
+        let mut ir_code = IrCode::new(&Program::from_string("[-]+++++-.+++[-]--"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.fold();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 4)));
+        assert_matches!(iter.next(), Some(IrOp::Write(0)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 3)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -2)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn trapping_folds_only_adjacent_ops() {
+        let mut ir_code = IrCode::new(&Program::from_string("+>+<+"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.fold();
+
+        assert_eq!(ir_code.len(), 3);
+    }
+
+    #[test]
+    fn propagates_constants() {
+        let mut ir_code = IrCode::new(&Program::from_string("[.]+[>.<-]>[.]"));
+        ir_code.eval_budget = 0;
+        ir_code.passes -= Optimizations::UNROLL;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::WriteConst(bytes)) if bytes == &[0]);
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), None);
+
+        let mut ir_code = IrCode::new(&Program::from_string("++++[-->+<]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 2)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn unrolls_loops_with_known_counter() {
+        let mut ir_code = IrCode::new(&Program::from_string(",>+++[>.<-]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), Some(IrOp::WriteConst(bytes)) if bytes == &[0, 0, 0]);
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), None);
+        assert_eq!(ir_code.report[&Optimizations::UNROLL], PassReport { rewrites: 1, removed: -3 });
+
+        let mut ir_code = IrCode::new(&Program::from_string("+++[>++[>+<-]<-]>>."));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+
+        assert!(ir_code.iter().all(|t| !matches!(t.op, IrOp::Loop(_))));
+        assert_matches!(ir_code.iter().find(|t| matches!(t.op, IrOp::WriteConst(_))).map(|t| &t.op), Some(IrOp::WriteConst(bytes)) if bytes == &[6]);
+    }
+
+    #[test]
+    fn keeps_loops_which_cannot_be_unrolled() {
+        for (source, overflow) in &[
+            (format!("{}[>.<-]", "+".repeat(40)), Overflow::Wrap),
+            ("++[>.<-->]".to_owned(), Overflow::Wrap),
+            ("+++[>.<--]".to_owned(), Overflow::Trap),
+            ("+++[>.<,]".to_owned(), Overflow::Wrap),
+        ] {
+            let mut ir_code = IrCode::new(&Program::from_string(source));
+            ir_code.overflow = *overflow;
+            ir_code.eval_budget = 0;
+            ir_code.optimize();
+
+            assert!(ir_code.iter().any(|t| matches!(t.op, IrOp::Loop(_))), "{}", source);
+            assert!(!ir_code.report.contains_key(&Optimizations::UNROLL), "{}", source);
+        }
+    }
+
+    #[test]
+    fn fuses_constant_writes() {
+        let mut ir_code = IrCode::new(&Program::from_string(",>++.+.>+++.<.<."));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 3)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(2, 3)));
+        assert_matches!(iter.next(), Some(IrOp::WriteConst(bytes)) if bytes == &[2, 3, 3, 3]);
+        assert_matches!(iter.next(), Some(IrOp::Write(0)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn propagates_constants_into_unknown_cells() {
+        let mut ir_code = IrCode::new(&Program::from_string(",>++[<+>-]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 2)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 0)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
         assert_matches!(iter.next(), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn forgets_constants_after_pointer_moves_by_unknown_amount() {
+        let mut ir_code = IrCode::new(&Program::from_string("[>]+>+<[>]<[.]>[.]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 1)));
+        assert_matches!(iter.next(), Some(IrOp::ScanRight(1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(-1)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::Write(0)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn propagation_keeps_trapping_ops() {
+        let mut ir_code = IrCode::new(&Program::from_string("+--"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -2)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn propagation_keeps_output_of_sample_programs() {
+        for file in &["hello_world.bf", "pi.bf", "sierpinski.bf", "squares.bf"] {
+            let path = format!("{}/sample_programs/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let program = Program::from_string(&std::fs::read_to_string(path).unwrap());
+
+            assert!(verify(&program, b"", Overflow::Wrap, Optimizations::all()).is_empty(), "{} differs", file);
+        }
+    }
+
+    #[test]
+    fn evaluates_input_free_prefix() {
+        let mut ir_code = IrCode::new(&Program::from_string("++++++[>++++++++<-]>+.+.,>+"));
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::WriteConst(bytes)) if bytes == b"12");
+        /* cell 1 is overwritten by input */
+        assert_matches!(iter.next(), Some(IrOp::Read(1)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(2, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(2)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn evaluates_whole_program_without_input() {
+        let path = format!("{}/sample_programs/hello_world.bf", env!("CARGO_MANIFEST_DIR"));
+        let mut ir_code = IrCode::new(&Program::from_string(&std::fs::read_to_string(path).unwrap()));
+        ir_code.optimize();
+
+        assert_matches!(&ir_code.ops[0].op, IrOp::WriteConst(bytes) if bytes == b"Hello World!\n");
+        assert!(ir_code.iter().all(|t| matches!(t.op, IrOp::WriteConst(_) | IrOp::SetIndirect(..) | IrOp::Move(_))));
+    }
+
+    #[test]
+    fn evaluation_stops_before_loop_exceeding_budget() {
+        let mut ir_code = IrCode::new(&Program::from_string("+++[>.<-]"));
+        ir_code.eval_budget = 3;
+        ir_code.passes -= Optimizations::UNROLL;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 3)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+
+        let mut ir_code = IrCode::new(&Program::from_string("+[]"));
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn evaluation_leaves_traps_to_run_time() {
+        let mut ir_code = IrCode::new(&Program::from_string("+.--"));
+        ir_code.overflow = Overflow::Trap;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::WriteConst(bytes)) if bytes == &[1]);
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 1)));
+        assert_matches!(iter.next(), Some(IrOp::Add(0, -2)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn eliminates_dead_stores() {
+        let mut ir_code = IrCode::new(&Program::from_string(",>+++<[->+<]>[-]<."));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 0)));
+        assert_matches!(iter.next(), Some(IrOp::WriteConst(bytes)) if bytes == &[0]);
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn eliminates_dead_stores_across_loops() {
+        let mut ir_code = IrCode::new(&Program::from_string(">+<,[.,]>[-]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::Write(0)));
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 0)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), None);
+
+        /* loop moving the pointer may read any cell */
+        let mut ir_code = IrCode::new(&Program::from_string(">+<,[>]>[-]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+
+        assert_matches!(ir_code.ops[0].op, IrOp::SetIndirect(1, 1));
+    }
+
+    #[test]
+    fn dead_store_elimination_keeps_trapping_ops() {
+        for (overflow, expected) in &[(Overflow::Wrap, 3), (Overflow::Trap, 4)] {
+            let mut ir_code = IrCode::new(&Program::from_string(",+>.<[-]"));
+            ir_code.overflow = *overflow;
+            ir_code.eval_budget = 0;
+            ir_code.optimize();
+
+            assert_eq!(ir_code.len(), *expected);
+        }
+    }
+
+    #[test]
+    fn dead_store_elimination_keeps_stores_before_traps() {
+        /* the first store is seen when the program traps */
+        for source in &["+>,-<++", "+>,[->-<]<++"] {
+            let mut ir_code = IrCode::new(&Program::from_string(source));
+            ir_code.overflow = Overflow::Trap;
+            ir_code.eval_budget = 0;
+            ir_code.optimize();
+
+            assert_matches!(ir_code.ops[0].op, IrOp::SetIndirect(0, 1));
+        }
+
+        /* counter loop with even step traps on odd cells in every mode */
+        for source in &[">>+++<<,[-->+<]>>[-]", ">>+++<<,[,[-->+<]]>>[-]"] {
+            let program = Program::from_string(source);
+            for overflow in &[Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+                assert!(verify(&program, b"\x03\x03", *overflow, Optimizations::all()).is_empty(), "{} differs", source);
+            }
+        }
+    }
+
+    #[test]
+    fn dead_store_elimination_keeps_output() {
+        let programs: [(&str, &[u8]); 4] = [
+            (",>+++<[->+<]>[-]<.", b"\x07"),
+            (">+<,[.,]>[-]>.", b"abc\0"),
+            (",[>+>++<<-]>[-]>.<<+++>[-]<.", b"\x05"),
+            ("+>,[<[-]+>-]<.>>+++[-]<.", b"\x09"),
+        ];
+
+        for (source, input) in programs.iter() {
+            let program = Program::from_string(source);
+            assert!(verify(&program, input, Overflow::Wrap, Optimizations::all()).is_empty(), "{} differs", source);
+            assert!(verify(&program, input, Overflow::Saturate, Optimizations::all()).is_empty(), "{} differs", source);
+        }
+    }
+
+    #[test]
+    fn lowers_run_once_loops_to_conditionals() {
+        let mut ir_code = IrCode::new(&Program::from_string(",[>+<[-]]>.,[.[-]>[-]<]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Read(0)));
+        assert_matches!(iter.next(), Some(IrOp::IfNonZero(_)));
+        /* cell 1 is known to be zero inside */
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(1, 1)));
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), Some(IrOp::Write(1)));
+        assert_matches!(iter.next(), Some(IrOp::Read(1)));
+        assert_matches!(iter.next(), Some(IrOp::Move(1)));
+        assert_matches!(iter.next(), Some(IrOp::IfNonZero(_)));
+        assert_matches!(iter.next(), Some(IrOp::Write(0)));
+        /* the cell to the right is known to be clear already */
+        assert_matches!(iter.next(), Some(IrOp::SetIndirect(0, 0)));
+        assert_matches!(iter.next(), None);
+    }
+
+    #[test]
+    fn keeps_loops_running_more_than_once() {
+        let mut ir_code = IrCode::new(&Program::from_string(",[.-]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+
+        assert_matches!(ir_code.ops[1].op, IrOp::Loop(_));
+    }
+
+    #[test]
+    fn inlines_conditionals_with_known_condition() {
+        let mut ir_code = IrCode::new(&Program::from_string(",>+[>+<[-]]<."));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+
+        assert!(ir_code.iter().all(|t| !matches!(t.op, IrOp::Loop(_) | IrOp::IfNonZero(_))));
+        assert!(verify(&Program::from_string(",>+[>+<[-]]<."), b"a", Overflow::Wrap, Optimizations::all()).is_empty());
+    }
+
+    #[test]
+    fn conditionals_keep_output() {
+        for source in &[",[>+<[-]]>.", ",[.[-]>[-]<]>.", ",>,<[>[.[-]]<[-]]>.", ",[>++<[-]]>[<+>[-]]<."] {
+            let program = Program::from_string(source);
+            for input in &[b"\0\0", b"\0\x05", b"\x03\0", b"\x03\x05"] {
+                assert!(verify(&program, *input, Overflow::Wrap, Optimizations::all()).is_empty(), "{} differs", source);
+            }
+        }
+    }
+
+    #[test]
+    fn reoptimizes_optimized_ir() {
+        for file in &["hello_world.bf", "pi.bf", "sierpinski.bf", "squares.bf"] {
+            let path = format!("{}/sample_programs/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let program = Program::from_string(&std::fs::read_to_string(path).unwrap());
+            let mut ir_code = IrCode::new(&program);
+            ir_code.eval_budget = 0;
+            ir_code.optimize();
+            let once = ir_code.to_string();
+            ir_code.optimize();
+
+            let mut expected = Evaluator::new(Overflow::Wrap, 100_000_000).with_input(Vec::new());
+            let mut actual = expected.clone();
+            expected.execute_block(&IrCode::parse(&once).unwrap().ops).unwrap();
+            actual.execute_block(&ir_code.ops).unwrap();
+            assert_eq!(actual.output, expected.output, "{}", file);
+        }
+    }
+
+    #[test]
+    fn levels_enable_passes() {
+        assert_eq!(Optimizations::level(0), Optimizations::empty());
+        assert!(Optimizations::level(2).contains(Optimizations::level(1)));
+        assert!(!Optimizations::level(2).contains(Optimizations::PARTIAL_EVALUATION));
+        assert_eq!(Optimizations::level(3), Optimizations::all());
+
+        for name in Optimizations::all().names() {
+            assert_eq!(Optimizations::from_name(name).unwrap().names(), vec![name]);
+        }
+        assert_eq!(Optimizations::from_name("loop-unswitching"), None);
+    }
+
+    #[test]
+    fn passes_keep_their_dependencies() {
+        let mut passes = Optimizations::level(1);
+        passes.enable(Optimizations::UNROLL);
+        assert!(passes.contains(Optimizations::UNROLL | Optimizations::CONSTANT_PROPAGATION));
+
+        let mut passes = Optimizations::all();
+        passes.disable(Optimizations::CONSTANT_PROPAGATION);
+        assert!(!passes.intersects(Optimizations::UNROLL | Optimizations::CONDITIONAL));
+        passes.disable(Optimizations::UNROLL);
+        assert!(passes.contains(Optimizations::PARTIAL_EVALUATION));
+    }
+
+    #[test]
+    fn runs_only_enabled_passes() {
+        let program = Program::from_string("+>+[-]<[->++<]>.");
+        let mut ir_code = IrCode::new(&program);
+        ir_code.passes = Optimizations::empty();
+        ir_code.optimize();
+
+        assert_eq!(ir_code.len(), IrCode::new(&program).len());
+        assert!(ir_code.report.is_empty());
+
+        let mut ir_code = IrCode::new(&program);
+        ir_code.passes = Optimizations::level(1) - Optimizations::CLEAR_LOOP;
+        ir_code.optimize();
+        let ops: Vec<_> = ir_code.iter().map(|t| &t.op).collect();
+
+        assert_matches!(ops[..], [IrOp::Add(0, 1), IrOp::Add(1, 1), IrOp::Move(1), IrOp::Loop(_), IrOp::Add(0, -1),
+            IrOp::Move(-1), IrOp::Loop(_), IrOp::Add(0, -1), IrOp::Add(1, 2), IrOp::Write(1), IrOp::Move(1)]);
+        assert!(!ir_code.report.contains_key(&Optimizations::CLEAR_LOOP));
+    }
+
+    #[test]
+    fn reports_removed_ops() {
+        let mut ir_code = IrCode::new(&Program::from_string("+++[-]>>,[->+<]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+
+        assert_eq!(ir_code.report[&Optimizations::CLEAR_LOOP], PassReport { rewrites: 1, removed: 1 });
+        assert_eq!(ir_code.report[&Optimizations::MULTIPLICATION_LOOP], PassReport { rewrites: 1, removed: 1 });
+
+        for file in &["hello_world.bf", "pi.bf", "sierpinski.bf", "squares.bf", "hanoi.bf"] {
+            let path = format!("{}/sample_programs/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let program = Program::from_string(&std::fs::read_to_string(path).unwrap());
+            let mut ir_code = IrCode::new(&program);
+            ir_code.optimize();
+
+            let removed: isize = ir_code.report.values().map(|t| t.removed).sum();
+            assert_eq!(removed, IrCode::new(&program).len() as isize - ir_code.len() as isize, "{}", file);
+        }
+    }
+
+    #[test]
+    fn counts_iterations() {
+        assert_eq!(iterations(0, 255), Some(0));
+        assert_eq!(iterations(5, 255), Some(5));
+        assert_eq!(iterations(6, 254), Some(3));
+        assert_eq!(iterations(5, 254), None);
+        assert_eq!(iterations(4, 1), Some(252));
+        assert_eq!(iterations(3, 0), None);
+        assert!((0..=255).all(|c: u8| (1..=255).all(|step: u8| match iterations(c, step) {
+            Some(n) => c.wrapping_add(n.wrapping_mul(step)) == 0 && (0..n).all(|t| c.wrapping_add(t.wrapping_mul(step)) != 0),
+            None => (0..=255).all(|t: u8| c.wrapping_add(t.wrapping_mul(step)) != 0),
+        })));
+    }
+
+    #[test]
+    fn optimized_ops_keep_locations() {
+        let mut ir_code = IrCode::new(&Program::from_string("+\n++>\n [-]"));
+        ir_code.fold();
+        let locations: Vec<String> = ir_code.iter()
+            .map(|t| t.origin.location.to_string())
+            .collect();
+
+        assert_eq!(locations, vec!["1:1", "3:2", "2:3"]);
+    }
+}
@@ -1,21 +1,41 @@
+use std::io::{Read, Write};
 use std::time::Instant;
-use crate::brainfuck::{Program, Op, MAX_MEMORY};
-use crate::interpreter::Interpreter;
-use clap::{App, Arg, ArgMatches};
-use crate::ir::IrCode;
+use crate::brainfuck::{Program, Op, Overflow, RuntimeError, MAX_MEMORY};
+use crate::interpreter::{Interpreter, LoopDetection};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use crate::ir::{IrCode, Optimizations};
 use crate::compiler::IoFn;
+use crate::observer::Profiler;
+use crate::tape::{Tape, SparseTape};
+use crate::verify::{verify, Report};
+use crate::eval::Evaluator;
+use crate::generator::Generator;
+use crate::reduce::{Divergence, Reducer};
 
 mod assembler;
 mod ir;
 mod compiler;
 mod brainfuck;
 mod interpreter;
+mod observer;
+mod tape;
+mod verify;
+mod eval;
+mod text;
+mod check;
+mod dot;
+mod generator;
+mod reduce;
+mod emit;
+mod bounds;
 
 #[cfg_attr(tarpaulin, skip)]
 fn main() {
+    let pass_names = Optimizations::all().names();
     let matches = App::new("bfjit")
         .version("v1.0")
         .author("Matej Kormuth <matej.kormuth@gmail.com>")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(Arg::with_name("interpreter")
             .short("i")
             .long("interpreter")
@@ -31,10 +51,93 @@ fn main() {
             .long("dump")
             .help("Dump intermediate representation of program")
         )
+        .arg(Arg::with_name("dump-dot")
+            .long("dump-dot")
+            .help("Dump control-flow graph of intermediate representation in Graphviz DOT language")
+        )
+        .arg(Arg::with_name("emit-bf")
+            .long("emit-bf")
+            .help("Print optimized program as brainfuck source")
+        )
+        .arg(Arg::with_name("dot-compare")
+            .long("dot-compare")
+            .help("Put graph of unoptimized program next to the optimized one in --dump-dot")
+            .requires("dump-dot")
+        )
         .arg(Arg::with_name("unoptimize")
             .short("u")
             .long("unoptimize")
-            .help("Disable brainfuck program optimization during IR stage")
+            .help("Disable brainfuck program optimization during IR stage (same as -O0)")
+        )
+        .arg(Arg::with_name("opt-level")
+            .short("O")
+            .long("opt-level")
+            .help("Optimization passes: 0 none, 1 folding of ops and simple loops, 2 also solved loops and data-flow passes, 3 also compile time evaluation and unrolling")
+            .takes_value(true)
+            .possible_values(&["0", "1", "2", "3"])
+            .default_value("3")
+            .global(true)
+        )
+        .arg(Arg::with_name("enable-pass")
+            .long("enable-pass")
+            .help("Enables optimization pass in addition to the optimization level")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .possible_values(&pass_names)
+            .global(true)
+        )
+        .arg(Arg::with_name("disable-pass")
+            .long("disable-pass")
+            .help("Disables optimization pass enabled by the optimization level")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .possible_values(&pass_names)
+            .global(true)
+        )
+        .arg(Arg::with_name("verify-ir")
+            .long("verify-ir")
+            .help("Check invariants of IR after each optimization pass (always done in debug builds)")
+            .global(true)
+        )
+        .arg(Arg::with_name("report-passes")
+            .long("report-passes")
+            .help("Print how many rewrites each optimization pass made and how many ops it removed")
+        )
+        .arg(Arg::with_name("profile")
+            .short("p")
+            .long("profile")
+            .help("Print execution statistics collected by interpreter")
+        )
+        .arg(Arg::with_name("overflow")
+            .long("overflow")
+            .help("Behaviour of cells when incremented over 255 or decremented under 0")
+            .takes_value(true)
+            .possible_values(&["wrap", "saturate", "trap"])
+            .default_value("wrap")
+            .global(true)
+        )
+        .arg(Arg::with_name("tape")
+            .long("tape")
+            .help("Memory used by interpreter: fixed array, growable vector or sparse pages")
+            .takes_value(true)
+            .possible_values(&["fixed", "vec", "sparse"])
+            .default_value("fixed")
+        )
+        .arg(Arg::with_name("detect-loops")
+            .long("detect-loops")
+            .help("Infinite loops reported by interpreter: none, loops which cannot change their cell or also repeated states of small loops")
+            .takes_value(true)
+            .possible_values(&["off", "static", "cycles"])
+            .default_value("static")
+        )
+        .arg(Arg::with_name("eval-budget")
+            .long("eval-budget")
+            .help("Steps of the input-free start of program executed at compile time (0 disables it)")
+            .takes_value(true)
+            .default_value("1000000")
+            .global(true)
         )
         .arg(Arg::with_name("INPUT")
             .required(true)
@@ -42,61 +145,378 @@ fn main() {
             .help("Specified brainfuck source file to use")
             .takes_value(true)
         )
+        .subcommand(SubCommand::with_name("verify")
+            .about("Runs program with interpreter, unoptimized and optimized JIT and compares the results")
+            .arg(Arg::with_name("INPUT")
+                .required(true)
+                .index(1)
+                .help("Specified brainfuck source file to use")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("input")
+                .long("input")
+                .help("File with input passed to the program")
+                .takes_value(true)
+            )
+        )
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs brainfuck program or textual IR with the JIT or the IR interpreter")
+            .arg(Arg::with_name("INPUT")
+                .required(true)
+                .index(1)
+                .help("Specified brainfuck source or IR file to use")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("ir")
+                .long("ir")
+                .help("Input file contains textual IR (as printed by --dump)")
+            )
+            .arg(Arg::with_name("interpreter")
+                .short("i")
+                .long("interpreter")
+                .help("Runs IR with the IR interpreter instead of the JIT (input is read whole before running)")
+            )
+        )
+        .subcommand(SubCommand::with_name("fuzz")
+            .about("Verifies random programs and saves those on which interpreter and JITs disagree")
+            .arg(Arg::with_name("seed")
+                .long("seed")
+                .help("Seed of the first generated program, following programs use next seeds")
+                .takes_value(true)
+                .default_value("0")
+            )
+            .arg(Arg::with_name("count")
+                .long("count")
+                .help("Number of programs to generate")
+                .takes_value(true)
+                .default_value("1000")
+            )
+            .arg(Arg::with_name("length")
+                .long("length")
+                .help("Approximate number of top-level pieces (runs of instructions and loops) of programs")
+                .takes_value(true)
+                .default_value("20")
+            )
+            .arg(Arg::with_name("out")
+                .long("out")
+                .help("Directory where failing programs (fuzz-SEED.bf) and their input (fuzz-SEED.in) are saved")
+                .takes_value(true)
+                .default_value(".")
+            )
+        )
+        .subcommand(SubCommand::with_name("reduce")
+            .about("Shrinks program on which interpreter and JITs disagree while keeping the disagreement")
+            .arg(Arg::with_name("INPUT")
+                .required(true)
+                .index(1)
+                .help("Specified brainfuck source file to use")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("input")
+                .long("input")
+                .help("File with input passed to the program")
+                .takes_value(true)
+            )
+            .arg(Arg::with_name("out")
+                .long("out")
+                .help("File where reduced program is saved (INPUT with .min.bf extension by default)")
+                .takes_value(true)
+            )
+        )
         .get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("verify") {
+        verify_command(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("run") {
+        run_command(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("fuzz") {
+        fuzz_command(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("reduce") {
+        reduce_command(matches);
+        return;
+    }
+
 
     let file = matches.value_of("INPUT").unwrap();
     let content = std::fs::read_to_string(file).expect("cannot read specified file");
     let program = Program::from_string(&content);
+    let overflow: Overflow = matches.value_of("overflow").unwrap().parse().unwrap();
 
     let start = Instant::now();
-    if matches.is_present("dump") {
+    if matches.is_present("dump-dot") {
+        let mut ir_code = IrCode::new(&program);
+        ir_code.overflow = overflow;
+        ir_code.eval_budget = eval_budget(&matches);
+        ir_code.passes = passes(&matches);
+        ir_code.verify_passes |= matches.is_present("verify-ir");
+        ir_code.optimize();
+
+        if matches.is_present("dot-compare") {
+            print!("{}", dot::to_dot(&[("unoptimized", &IrCode::new(&program)), ("optimized", &ir_code)]));
+        } else {
+            print!("{}", dot::to_dot(&[("optimized", &ir_code)]));
+        }
+    } else if matches.is_present("emit-bf") {
         let mut ir_code = IrCode::new(&program);
+        ir_code.overflow = overflow;
+        ir_code.eval_budget = eval_budget(&matches);
+        ir_code.passes = passes(&matches);
+        ir_code.verify_passes |= matches.is_present("verify-ir");
+        ir_code.optimize();
 
-        if !matches.is_present("unoptimize") {
-            ir_code.optimize();
+        match ir_code.to_brainfuck() {
+            Ok(source) => source.as_bytes().chunks(80).for_each(|t| println!("{}", std::str::from_utf8(t).unwrap())),
+            Err(e) => {
+                eprintln!("error: {}: {}", file, e);
+                std::process::exit(1);
+            }
         }
+    } else if matches.is_present("dump") {
+        let mut ir_code = IrCode::new(&program);
+        ir_code.overflow = overflow;
+        ir_code.eval_budget = eval_budget(&matches);
+        ir_code.passes = passes(&matches);
+        ir_code.verify_passes |= matches.is_present("verify-ir");
+        ir_code.optimize();
 
-        println!("{:?}", ir_code);
-    } else if matches.is_present("interpreter") {
-        interpreter(&program);
-        println!("time={}ms (interpreter)", start.elapsed().as_millis())
+        print!("{}", ir_code);
+        if matches.is_present("report-passes") {
+            print_pass_report(&ir_code);
+        }
+    } else if matches.is_present("interpreter") || matches.is_present("profile")
+        || matches.occurrences_of("tape") > 0 || matches.occurrences_of("detect-loops") > 0 {
+        let profile = matches.is_present("profile");
+        let loop_detection: LoopDetection = matches.value_of("detect-loops").unwrap().parse().unwrap();
+        let result = match matches.value_of("tape").unwrap() {
+            "vec" => interpreter(&program, Vec::new(), overflow, loop_detection, profile),
+            "sparse" => interpreter(&program, SparseTape::default(), overflow, loop_detection, profile),
+            _ => interpreter(&program, [0; MAX_MEMORY], overflow, loop_detection, profile),
+        };
+        println!("time={}ms (interpreter)", start.elapsed().as_millis());
+        exit_on_error(result);
     } else {
-        let does_optimize = if matches.is_present("unoptimize") { "unoptimized" } else { "optimized" };
-        jit(matches, &program);
-        println!("time={}ms (jit; {})", start.elapsed().as_millis(), does_optimize)
+        let does_optimize = if passes(&matches).is_empty() { "unoptimized" } else { "optimized" };
+        let result = jit(matches, &program, overflow);
+        println!("time={}ms (jit; {})", start.elapsed().as_millis(), does_optimize);
+        exit_on_error(result);
     }
 }
 
 #[cfg_attr(tarpaulin, skip)]
-fn jit(matches: ArgMatches, program: &Program) {
-    let start = Instant::now();
-    let mut ir_code = IrCode::new(&program);
+fn verify_command(matches: &ArgMatches) {
+    let file = matches.value_of("INPUT").unwrap();
+    let content = std::fs::read_to_string(file).expect("cannot read specified file");
+    let program = Program::from_string(&content);
+    let overflow: Overflow = matches.value_of("overflow").unwrap().parse().unwrap();
+    let input = match matches.value_of("input") {
+        Some(t) => std::fs::read(t).expect("cannot read input file"),
+        None => Vec::new(),
+    };
 
-    let unopt_len = ir_code.len();
+    let mismatches = verify(&program, &input, overflow, passes(matches));
+    if mismatches.is_empty() {
+        println!("ok: interpreter, unoptimized jit and optimized jit agree");
+        return;
+    }
 
-    if !matches.is_present("unoptimize") {
-        ir_code.optimize();
+    for mismatch in mismatches.iter() {
+        println!("{}", Report(&program, mismatch));
+    }
+    std::process::exit(1);
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn fuzz_command(matches: &ArgMatches) {
+    let number = |name| matches.value_of(name).unwrap().parse().unwrap_or_else(|_| {
+        eprintln!("error: --{} must be a number", name);
+        std::process::exit(1);
+    });
+    let (seed, count, length): (u64, u64, u64) = (number("seed"), number("count"), number("length"));
+    let overflow: Overflow = matches.value_of("overflow").unwrap().parse().unwrap();
+    let out = std::path::Path::new(matches.value_of("out").unwrap());
+    let mut failures = 0;
+
+    for seed in seed..seed + count {
+        let mut generator = Generator::new(seed);
+        generator.length = length as usize;
+        generator.overflow = overflow;
+        let generated = generator.generate();
+        let program = Program::from_string(&generated.source);
+
+        let mismatches = verify(&program, &generated.input, overflow, passes(matches));
+        if mismatches.is_empty() { continue; }
+
+        let path = out.join(format!("fuzz-{}", seed));
+        std::fs::write(path.with_extension("bf"), &generated.source).expect("cannot write failing program");
+        std::fs::write(path.with_extension("in"), &generated.input).expect("cannot write failing input");
+        println!("seed {}: saved {}", seed, path.with_extension("bf").display());
+        for mismatch in mismatches.iter() {
+            println!("  {}", Report(&program, mismatch));
+        }
+        failures += 1;
+    }
+
+    println!("{} of {} programs failed", failures, count);
+    if failures > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn reduce_command(matches: &ArgMatches) {
+    let file = matches.value_of("INPUT").unwrap();
+    let content = std::fs::read_to_string(file).expect("cannot read specified file");
+    let overflow: Overflow = matches.value_of("overflow").unwrap().parse().unwrap();
+    let input = match matches.value_of("input") {
+        Some(t) => std::fs::read(t).expect("cannot read input file"),
+        None => Vec::new(),
+    };
+    let out = match matches.value_of("out") {
+        Some(t) => std::path::PathBuf::from(t),
+        None => std::path::Path::new(file).with_extension("min.bf"),
+    };
+
+    let divergence = Divergence::new(&content, &input, overflow, passes(matches)).unwrap_or_else(|e| {
+        eprintln!("error: {}: {}", file, e);
+        std::process::exit(1);
+    });
+    let mut reducer = Reducer::new(|source: &str| divergence.preserved(source));
+    let reduced = reducer.reduce(&content);
+    std::fs::write(&out, &reduced).expect("cannot write reduced program");
+
+    let program = Program::from_string(&reduced);
+    println!("reduced {} to {} instructions ({} candidates tried), saved {}",
+             Program::from_string(&content).instructions.len(), program.instructions.len(), reducer.candidates, out.display());
+    for mismatch in verify(&program, &input, overflow, passes(matches)).iter() {
+        println!("{}", Report(&program, mismatch));
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn run_command(matches: &ArgMatches) {
+    let file = matches.value_of("INPUT").unwrap();
+    let content = std::fs::read_to_string(file).expect("cannot read specified file");
+    let mut ir_code = if matches.is_present("ir") {
+        IrCode::parse(&content).unwrap_or_else(|e| {
+            eprintln!("error: {}: {}", file, e);
+            std::process::exit(1);
+        })
+    } else {
+        IrCode::new(&Program::from_string(&content))
+    };
+    ir_code.overflow = matches.value_of("overflow").unwrap().parse().unwrap();
+    if let Err(e) = ir_code.verify() {
+        eprintln!("error: {}: {}", file, e);
+        std::process::exit(1);
     }
+    ir_code.eval_budget = eval_budget(matches);
+    ir_code.passes = passes(matches);
+    ir_code.verify_passes |= matches.is_present("verify-ir");
+    ir_code.optimize();
 
+    if !matches.is_present("interpreter") {
+        exit_on_error(ir_code.compile(IoFn::std()).execute());
+        return;
+    }
+
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input).expect("cannot read from stdin");
+    let mut evaluator = Evaluator::new(ir_code.overflow, usize::MAX).with_input(input);
+    let result = evaluator.execute_block(&ir_code.ops);
+    std::io::stdout().write_all(&evaluator.output).expect("cannot write to stdout");
+    if result.is_err() {
+        eprintln!("error: program trapped or pointer left memory");
+        std::process::exit(1);
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn eval_budget(matches: &ArgMatches) -> usize {
+    matches.value_of("eval-budget").unwrap().parse().expect("eval budget must be a number")
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn passes(matches: &ArgMatches) -> Optimizations {
+    if matches.is_present("unoptimize") {
+        return Optimizations::empty();
+    }
+
+    let mut passes = Optimizations::level(matches.value_of("opt-level").unwrap().parse().unwrap());
+    for name in matches.values_of("enable-pass").into_iter().flatten() {
+        passes.enable(Optimizations::from_name(name).unwrap());
+    }
+    for name in matches.values_of("disable-pass").into_iter().flatten() {
+        passes.disable(Optimizations::from_name(name).unwrap());
+    }
+    passes
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn print_pass_report(ir_code: &IrCode) {
+    for (pass, report) in ir_code.report.iter() {
+        println!("pass={}\trewrites={}\tremoved={}", pass.names().join(","), report.rewrites, report.removed);
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn exit_on_error(result: Result<(), RuntimeError>) {
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg_attr(tarpaulin, skip)]
+fn jit(matches: ArgMatches, program: &Program, overflow: Overflow) -> Result<(), RuntimeError> {
+    let start = Instant::now();
+    let mut ir_code = IrCode::new(program);
+    ir_code.overflow = overflow;
+    ir_code.eval_budget = eval_budget(&matches);
+    ir_code.passes = passes(&matches);
+    ir_code.verify_passes |= matches.is_present("verify-ir");
+
+    let unopt_len = ir_code.len();
+    ir_code.optimize();
     let opt_len = ir_code.len();
 
+    if matches.is_present("report-passes") {
+        print_pass_report(&ir_code);
+    }
+
+    let tape = match ir_code.pointer_bounds().tape_size() {
+        Some(t) => t.to_string(),
+        None => "unbounded".to_owned(),
+    };
     let brainfuck = ir_code.compile(IoFn::std());
-    println!("compile_time={}ms\tunopt={}\topt={}\tbytes={} of {} allocated ({:.2}% used)", start.elapsed().as_millis(),
-             unopt_len, opt_len, brainfuck.length, brainfuck.program.len(), 100f32 * brainfuck.length as f32 / brainfuck.program.len() as f32);
-    brainfuck.execute();
+    println!("compile_time={}ms\tunopt={}\topt={}\tbytes={} of {} allocated ({:.2}% used)\ttape={}", start.elapsed().as_millis(),
+             unopt_len, opt_len, brainfuck.length, brainfuck.program.len(), 100f32 * brainfuck.length as f32 / brainfuck.program.len() as f32, tape);
+    brainfuck.execute()
 }
 
 #[cfg_attr(tarpaulin, skip)]
-fn interpreter(program: &Program) {
-    let mut vm = Interpreter {
-        program_counter: 0,
-        program: &program,
-        memory_pointer: 0,
-        memory: [0; MAX_MEMORY],
-        input: std::io::stdin(),
-        output: std::io::stdout(),
-    };
-    vm.interpret();
+fn interpreter<T: Tape>(program: &Program, tape: T, overflow: Overflow, loop_detection: LoopDetection, profile: bool) -> Result<(), RuntimeError> {
+    let mut vm = Interpreter::new(program, std::io::stdin(), std::io::stdout()).with_tape(tape);
+    vm.overflow = overflow;
+    vm.loop_detection = loop_detection;
+
+    if profile {
+        let mut vm = vm.with_observer(Profiler::default());
+        let result = vm.interpret();
+        let p = &vm.observer;
+        println!("steps={}\tloops_entered={}\tcell_writes={}\tread={}\twritten={}\thighest_address={}",
+                 p.steps, p.loops_entered, p.cell_writes, p.bytes_read, p.bytes_written, p.highest_address);
+        result
+    } else {
+        vm.interpret()
+    }
 }
 
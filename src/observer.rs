@@ -0,0 +1,169 @@
+use crate::brainfuck::Op;
+
+/// Input or output performed by the program together with the transferred byte.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Io {
+    Read(u8),
+    Write(u8),
+}
+
+/// Hooks into the execution of `Interpreter`.
+///
+/// Every callback has an empty default implementation so an observer only
+/// implements the events it is interested in. Positions passed to callbacks
+/// are indices into `Program::instructions`.
+pub trait ExecutionObserver {
+    /// Called before the instruction at `position` is executed.
+    ///
+    /// A loop jumps back from `]` directly into its body, so `[` is only seen
+    /// once per loop entry, not once per iteration.
+    #[inline(always)]
+    fn on_step(&mut self, _position: usize, _op: Op) {}
+
+    /// Called when loop starting at `position` is entered (its condition was non-zero).
+    #[inline(always)]
+    fn on_loop_enter(&mut self, _position: usize) {}
+
+    /// Called when loop ending at `position` is left (its condition became zero).
+    #[inline(always)]
+    fn on_loop_exit(&mut self, _position: usize) {}
+
+    /// Called after a memory cell at `address` was changed to `value`.
+    #[inline(always)]
+    fn on_cell_write(&mut self, _address: usize, _value: u8) {}
+
+    /// Called after the instruction at `position` read or wrote a byte.
+    #[inline(always)]
+    fn on_io(&mut self, _position: usize, _io: Io) {}
+}
+
+/// Observer that ignores all events. Used by default so plain interpretation
+/// pays nothing for the hooks.
+pub struct NoopObserver;
+
+impl ExecutionObserver for NoopObserver {}
+
+/// Observer collecting simple execution statistics.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pub steps: u64,
+    pub loops_entered: u64,
+    pub cell_writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub highest_address: usize,
+}
+
+impl ExecutionObserver for Profiler {
+    fn on_step(&mut self, _position: usize, _op: Op) {
+        self.steps += 1;
+    }
+
+    fn on_loop_enter(&mut self, _position: usize) {
+        self.loops_entered += 1;
+    }
+
+    fn on_cell_write(&mut self, address: usize, _value: u8) {
+        self.cell_writes += 1;
+        self.highest_address = self.highest_address.max(address);
+    }
+
+    fn on_io(&mut self, _position: usize, io: Io) {
+        match io {
+            Io::Read(_) => self.bytes_read += 1,
+            Io::Write(_) => self.bytes_written += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Op, Program};
+    use crate::interpreter::Interpreter;
+    use crate::observer::{ExecutionObserver, Io, Profiler};
+    use std::io::Cursor;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    impl ExecutionObserver for Recorder {
+        fn on_loop_enter(&mut self, position: usize) {
+            self.events.push(format!("enter {}", position));
+        }
+
+        fn on_loop_exit(&mut self, position: usize) {
+            self.events.push(format!("exit {}", position));
+        }
+
+        fn on_cell_write(&mut self, address: usize, value: u8) {
+            self.events.push(format!("cell {}={}", address, value));
+        }
+
+        fn on_io(&mut self, position: usize, io: Io) {
+            self.events.push(format!("io {} {:?}", position, io));
+        }
+    }
+
+    #[test]
+    fn reports_events_in_order() {
+        let program = Program::from_string(",[->+<]>.");
+        let mut output = Vec::new();
+        let mut vm = Interpreter::new(&program, Cursor::new(b"\x01"), &mut output)
+            .with_observer(Recorder::default());
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.observer.events, vec![
+            "io 0 Read(1)",
+            "cell 0=1",
+            "enter 1",
+            "cell 0=0",
+            "cell 1=1",
+            "exit 6",
+            "io 8 Write(1)",
+        ]);
+    }
+
+    #[test]
+    fn profiler_counts_steps() {
+        let program = Program::from_string("++[>+<-]>.");
+        let mut output = Vec::new();
+        let mut vm = Interpreter::new(&program, std::io::empty(), &mut output)
+            .with_observer(Profiler::default());
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.observer.steps, 2 + 1 + 2 * 5 + 1 + 1);
+        assert_eq!(vm.observer.loops_entered, 1);
+        assert_eq!(vm.observer.cell_writes, 2 + 2 * 2);
+        assert_eq!(vm.observer.bytes_written, 1);
+        assert_eq!(vm.observer.highest_address, 1);
+    }
+
+    #[test]
+    fn steps_see_every_op() {
+        struct Steps(Vec<Op>);
+        impl ExecutionObserver for Steps {
+            fn on_step(&mut self, _position: usize, op: Op) {
+                self.0.push(op);
+            }
+        }
+
+        let program = Program::from_string("+[-]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink())
+            .with_observer(Steps(Vec::new()));
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.observer.0, vec![Op::IncrementMemory, Op::JumpForward, Op::DecrementMemory, Op::JumpBackward]);
+    }
+
+    #[test]
+    fn steps_see_loop_start_once_per_entry() {
+        let program = Program::from_string("+++[-]");
+        let mut vm = Interpreter::new(&program, std::io::empty(), std::io::sink())
+            .with_observer(Profiler::default());
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.observer.steps, 3 + 1 + 3 * 2);
+    }
+}
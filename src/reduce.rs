@@ -0,0 +1,281 @@
+use std::fmt::{Display, Formatter};
+
+use crate::brainfuck::{Program, Overflow, MAX_MEMORY};
+use crate::eval::{Evaluator, Halt};
+use crate::ir::{IrCode, IrNode, IrOp, Optimizations};
+use crate::verify::verify;
+
+/// Steps of the IR interpreter the original program may take.
+const MAX_STEPS: usize = 100_000_000;
+
+/// Reason why a program cannot be reduced.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReduceError {
+    /* interpreter and both JITs agree on the program */
+    NoDivergence,
+    /* program runs for too long, leaves memory or reads past the end of input */
+    Invalid,
+}
+
+impl Display for ReduceError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            ReduceError::NoDivergence => write!(f, "interpreter and JITs agree on the program, nothing to reduce"),
+            ReduceError::Invalid => write!(f, "program must finish in {} steps, keep the pointer in memory and not read past the end of input", MAX_STEPS),
+        }
+    }
+}
+
+/// Divergence of JITs from the interpreter on a program, which reduced programs must keep.
+pub struct Divergence<'a> {
+    input: &'a [u8],
+    overflow: Overflow,
+    passes: Optimizations,
+    /* JITs which disagree with the interpreter */
+    backends: Vec<&'static str>,
+    /* steps of the IR interpreter a candidate may take */
+    budget: usize,
+}
+
+impl<'a> Divergence<'a> {
+    pub fn new(source: &str, input: &'a [u8], overflow: Overflow, passes: Optimizations) -> Result<Self, ReduceError> {
+        let mut divergence = Divergence { input, overflow, passes, backends: Vec::new(), budget: MAX_STEPS };
+        let program = Program::from_string(source);
+        let steps = divergence.steps(&program).ok_or(ReduceError::Invalid)?;
+
+        divergence.backends = divergence.backends(&program);
+        if divergence.backends.is_empty() {
+            return Err(ReduceError::NoDivergence);
+        }
+        /* removing instructions may make loops run longer, but not much */
+        divergence.budget = steps.saturating_mul(4).saturating_add(100_000);
+        Ok(divergence)
+    }
+
+    /// Steps the program takes when run by the IR interpreter. Programs which exceed the
+    /// budget, move the pointer outside of memory or read past the end of input would hang
+    /// or crash the interpreter.
+    fn steps(&self, program: &Program) -> Option<usize> {
+        let mut ops = IrCode::new(program).ops;
+        probe_moves(&mut ops);
+
+        /* extra byte stays unread unless the program reads past the end */
+        let input = self.input.iter().copied().chain(std::iter::once(0)).collect();
+        let mut evaluator = Evaluator::new(self.overflow, self.budget).with_input(input);
+        match evaluator.execute_block(&ops) {
+            Ok(()) => {}
+            /* trapping cell, the interpreter stops as well */
+            Err(Halt::Fault) if evaluator.pointer >= 0 && (evaluator.pointer as usize) < MAX_MEMORY => {}
+            Err(_) => return None,
+        }
+
+        if evaluator.input.unwrap().is_empty() { None } else { Some(self.budget - evaluator.budget) }
+    }
+
+    fn backends(&self, program: &Program) -> Vec<&'static str> {
+        verify(program, self.input, self.overflow, self.passes).iter().map(|t| t.backend).collect()
+    }
+
+    /// Whether the same JITs disagree with the interpreter on `source`.
+    pub fn preserved(&self, source: &str) -> bool {
+        let program = Program::from_string(source);
+        self.steps(&program).is_some() && self.backends(&program) == self.backends
+    }
+}
+
+/// Makes the IR interpreter touch the cell after each move to the left, so that it halts
+/// when the pointer leaves memory like the interpreter would.
+fn probe_moves(block: &mut Vec<IrNode>) {
+    let mut idx = 0;
+    while idx < block.len() {
+        match &mut block[idx].op {
+            IrOp::Move(x) if *x < 0 => {
+                let probe = IrNode { op: IrOp::Add(0, 0), origin: block[idx].origin };
+                block.insert(idx + 1, probe);
+                idx += 1;
+            }
+            IrOp::Loop(body) => probe_moves(body),
+            _ => {}
+        }
+        idx += 1;
+    }
+}
+
+/// Whether `code` can be removed from a program without unbalancing its loops.
+fn balanced(code: &[u8]) -> bool {
+    let mut level = 0;
+    for c in code {
+        match c {
+            b'[' => level += 1,
+            b']' if level == 0 => return false,
+            b']' => level -= 1,
+            _ => {}
+        }
+    }
+    level == 0
+}
+
+fn find_loop_end(code: &[u8], start: usize) -> usize {
+    let mut level = 0;
+    for (idx, c) in code.iter().enumerate().skip(start) {
+        match c {
+            b'[' => level += 1,
+            b']' if level == 1 => return idx,
+            b']' => level -= 1,
+            _ => {}
+        }
+    }
+    unreachable!("loops are balanced")
+}
+
+/// Delta debugging reducer of brainfuck programs. Every accepted reduction makes the
+/// program shorter, so it stops when no reduction keeps the program interesting.
+pub struct Reducer<F: FnMut(&str) -> bool> {
+    interesting: F,
+    /* number of candidates tried */
+    pub candidates: usize,
+}
+
+impl<F: FnMut(&str) -> bool> Reducer<F> {
+    pub fn new(interesting: F) -> Self {
+        Reducer { interesting, candidates: 0 }
+    }
+
+    fn try_candidate(&mut self, code: &mut Vec<u8>, candidate: Vec<u8>) -> bool {
+        self.candidates += 1;
+        if !(self.interesting)(std::str::from_utf8(&candidate).unwrap()) {
+            return false;
+        }
+        *code = candidate;
+        true
+    }
+
+    /// Shortest program found from `source` which is still interesting. Comments are
+    /// removed first.
+    pub fn reduce(&mut self, source: &str) -> String {
+        let mut code: Vec<u8> = source.bytes().filter(|c| b"+-<>[].,".contains(c)).collect();
+
+        loop {
+            let length = code.len();
+            self.delete_substrings(&mut code);
+            self.drop_loops(&mut code);
+            self.shorten_runs(&mut code);
+            if code.len() == length { break; }
+        }
+
+        String::from_utf8(code).unwrap()
+    }
+
+    /// Deletes balanced substrings, from half of the program down to single instructions.
+    fn delete_substrings(&mut self, code: &mut Vec<u8>) {
+        let mut size = code.len() / 2;
+        while size > 0 {
+            let mut start = 0;
+            while start + size <= code.len() {
+                if balanced(&code[start..start + size]) {
+                    let candidate = [&code[..start], &code[start + size..]].concat();
+                    if self.try_candidate(code, candidate) { continue; }
+                }
+                start += 1;
+            }
+            size /= 2;
+        }
+    }
+
+    /// Deletes whole loops or replaces them by their body.
+    fn drop_loops(&mut self, code: &mut Vec<u8>) {
+        let mut start = 0;
+        while start < code.len() {
+            if code[start] != b'[' {
+                start += 1;
+                continue;
+            }
+
+            let end = find_loop_end(code, start);
+            let deleted = [&code[..start], &code[end + 1..]].concat();
+            if self.try_candidate(code, deleted) { continue; }
+            let unwrapped = [&code[..start], &code[start + 1..end], &code[end + 1..]].concat();
+            if self.try_candidate(code, unwrapped) { continue; }
+            start += 1;
+        }
+    }
+
+    /// Replaces runs of `+`, `-`, `<` or `>` by a half as long run or by a shorter run in
+    /// the other direction (cells wrap at 256).
+    fn shorten_runs(&mut self, code: &mut Vec<u8>) {
+        let mut start = 0;
+        while start < code.len() {
+            let c = code[start];
+            let length = code[start..].iter().take_while(|t| **t == c).count();
+            if !b"+-<>".contains(&c) || length < 2 {
+                start += length;
+                continue;
+            }
+
+            let mut replacements = vec![vec![c; length / 2]];
+            if (c == b'+' || c == b'-') && 256 - length < length {
+                replacements.insert(0, vec![if c == b'+' { b'-' } else { b'+' }; 256 - length]);
+            }
+            let reduced = replacements.into_iter()
+                .any(|run| self.try_candidate(code, [&code[..start], &run, &code[start + length..]].concat()));
+            if !reduced {
+                start += length;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Program, Overflow};
+    use crate::eval::Evaluator;
+    use crate::ir::{IrCode, Optimizations};
+    use crate::reduce::{Divergence, Reducer, ReduceError};
+
+    fn output(source: &str) -> Vec<u8> {
+        let mut evaluator = Evaluator::new(Overflow::Wrap, 100_000);
+        let _ = evaluator.execute_block(&IrCode::new(&Program::from_string(source)).ops);
+        evaluator.output
+    }
+
+    #[test]
+    fn reduces_to_minimal_program() {
+        let mut reducer = Reducer::new(|source: &str| output(source).first() == Some(&3));
+
+        assert_eq!(reducer.reduce("comment ++>[-]+<+.>+++.,"), "+++.");
+        assert!(reducer.candidates > 0);
+    }
+
+    #[test]
+    fn keeps_needed_loops() {
+        let mut reducer = Reducer::new(|source: &str| output(source).starts_with(&[3, 2, 1]));
+
+        assert_eq!(reducer.reduce("+++>++<[.->+<]>>+."), "+++[.-]");
+    }
+
+    #[test]
+    fn shortens_runs_modulo_256() {
+        let mut reducer = Reducer::new(|source: &str| output(source) == [250]);
+
+        assert_eq!(reducer.reduce(&format!("{}.", "+".repeat(250))), "------.");
+    }
+
+    #[test]
+    fn needs_divergence() {
+        let result = Divergence::new("+[->+<]>.", b"", Overflow::Wrap, Optimizations::all());
+
+        assert_eq!(result.err(), Some(ReduceError::NoDivergence));
+    }
+
+    #[test]
+    fn rejects_invalid_candidates() {
+        let divergence = Divergence { input: b"a", overflow: Overflow::Trap, passes: Optimizations::all(), backends: Vec::new(), budget: 1000 };
+
+        assert!(divergence.preserved("+.,."));
+        assert!(divergence.preserved("-"));
+        assert!(!divergence.preserved(",,"));
+        assert!(!divergence.preserved("<>"));
+        assert!(!divergence.preserved("+[]"));
+        assert!(!divergence.preserved("+[>+]"));
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use memchr::{memchr, memrchr};
+use crate::brainfuck::MAX_MEMORY;
+
+/// Memory cells of the interpreter. All cells start as zero.
+pub trait Tape {
+    fn get(&self, address: usize) -> u8;
+    fn set(&mut self, address: usize, value: u8);
+
+    /// Returns address of the first zero cell among `from`, `from + stride`,
+    /// `from + 2 * stride`, ... Panics if the search leaves the memory to the left.
+    fn find_zero(&self, from: usize, stride: isize) -> usize {
+        let mut address = from;
+        while self.get(address) != 0 {
+            address = offset_address(address, stride);
+        }
+        address
+    }
+}
+
+#[inline]
+fn offset_address(address: usize, stride: isize) -> usize {
+    if stride >= 0 {
+        address + stride as usize
+    } else {
+        address.checked_sub(stride.unsigned_abs()).expect("pointer moved to the left of memory")
+    }
+}
+
+/// Finds zero cell in `cells` with `memchr` for stride 1 and -1. Cells past the end of
+/// `cells` are zero when `zero_past_end`, otherwise the search panics there.
+fn find_zero_in_slice(cells: &[u8], from: usize, stride: isize, zero_past_end: bool) -> usize {
+    /* first address past the end of `cells` reached by the search */
+    let past_end = |address: usize| if zero_past_end { address } else { panic!("pointer moved past the end of memory") };
+
+    match stride {
+        1 => cells.get(from..).and_then(|t| memchr(0, t)).map(|t| from + t).unwrap_or_else(|| past_end(cells.len().max(from))),
+        -1 if from >= cells.len() => from,
+        -1 => memrchr(0, &cells[..=from]).expect("pointer moved to the left of memory"),
+        _ => {
+            let mut address = from;
+            while address < cells.len() && cells[address] != 0 {
+                address = offset_address(address, stride);
+            }
+            if address < cells.len() { address } else { past_end(address) }
+        }
+    }
+}
+
+/// Fixed array of `MAX_MEMORY` cells. Accessing cell outside of it panics.
+impl Tape for [u8; MAX_MEMORY] {
+    #[inline]
+    fn get(&self, address: usize) -> u8 {
+        self[address]
+    }
+
+    #[inline]
+    fn set(&mut self, address: usize, value: u8) {
+        self[address] = value;
+    }
+
+    fn find_zero(&self, from: usize, stride: isize) -> usize {
+        find_zero_in_slice(self, from, stride, false)
+    }
+}
+
+/// Tape which grows to the right as cells are written.
+impl Tape for Vec<u8> {
+    #[inline]
+    fn get(&self, address: usize) -> u8 {
+        self.as_slice().get(address).copied().unwrap_or(0)
+    }
+
+    #[inline]
+    fn set(&mut self, address: usize, value: u8) {
+        if address >= self.len() {
+            self.resize(address + 1, 0);
+        }
+        self[address] = value;
+    }
+
+    fn find_zero(&self, from: usize, stride: isize) -> usize {
+        find_zero_in_slice(self, from, stride, true)
+    }
+}
+
+/// Number of cells in one page of `SparseTape`.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Tape which allocates pages of cells only when they are written to. Suitable for
+/// programs which touch a few cells spread over huge addresses.
+#[derive(Default)]
+pub struct SparseTape {
+    pages: HashMap<usize, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl Tape for SparseTape {
+    #[inline]
+    fn get(&self, address: usize) -> u8 {
+        self.pages.get(&(address / PAGE_SIZE))
+            .map(|page| page[address % PAGE_SIZE])
+            .unwrap_or(0)
+    }
+
+    #[inline]
+    fn set(&mut self, address: usize, value: u8) {
+        self.pages.entry(address / PAGE_SIZE)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE]))[address % PAGE_SIZE] = value;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Program, MAX_MEMORY};
+    use crate::interpreter::Interpreter;
+    use crate::ir::IrCode;
+    use crate::tape::{Tape, SparseTape, PAGE_SIZE};
+    use crate::verify::run_jit;
+
+    const SAMPLE_PROGRAMS: [&str; 4] = ["hello_world.bf", "pi.bf", "sierpinski.bf", "squares.bf"];
+    const SLOW_SAMPLE_PROGRAMS: [&str; 3] = ["hanoi.bf", "long.bf", "mandelbrot.bf"];
+
+    /// Runs sample programs with the interpreter using tape created by `make_tape`
+    /// and checks output and memory against unoptimized JIT.
+    fn run_sample_programs<T: Tape>(files: &[&str], make_tape: fn() -> T) {
+        for file in files {
+            let path = format!("{}/sample_programs/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let program = Program::from_string(&std::fs::read_to_string(path).unwrap());
+            let expected = run_jit(&mut IrCode::new(&program), b"");
+
+            let mut output = Vec::new();
+            let mut vm = Interpreter::new(&program, std::io::empty(), &mut output)
+                .with_tape(make_tape());
+            vm.interpret().unwrap();
+            let memory: Vec<u8> = (0..MAX_MEMORY).map(|t| vm.memory.get(t)).collect();
+
+            assert_eq!(memory, expected.memory, "memory of {} differs", file);
+            assert_eq!(output, expected.output, "output of {} differs", file);
+        }
+    }
+
+    #[test]
+    fn fixed_tape_runs_sample_programs() {
+        run_sample_programs(&SAMPLE_PROGRAMS, || [0; MAX_MEMORY]);
+    }
+
+    #[test]
+    fn vec_tape_runs_sample_programs() {
+        run_sample_programs(&SAMPLE_PROGRAMS, Vec::new);
+    }
+
+    #[test]
+    fn sparse_tape_runs_sample_programs() {
+        run_sample_programs(&SAMPLE_PROGRAMS, SparseTape::default);
+    }
+
+    #[test]
+    #[ignore = "slow in debug builds, run with `cargo test --release -- --ignored`"]
+    fn fixed_tape_runs_slow_sample_programs() {
+        run_sample_programs(&SLOW_SAMPLE_PROGRAMS, || [0; MAX_MEMORY]);
+    }
+
+    #[test]
+    #[ignore = "slow in debug builds, run with `cargo test --release -- --ignored`"]
+    fn vec_tape_runs_slow_sample_programs() {
+        run_sample_programs(&SLOW_SAMPLE_PROGRAMS, Vec::new);
+    }
+
+    #[test]
+    #[ignore = "slow in debug builds, run with `cargo test --release -- --ignored`"]
+    fn sparse_tape_runs_slow_sample_programs() {
+        run_sample_programs(&SLOW_SAMPLE_PROGRAMS, SparseTape::default);
+    }
+
+    #[test]
+    fn vec_tape_grows() {
+        let mut tape = Vec::new();
+
+        assert_eq!(tape.get(100), 0);
+        tape.set(100, 7);
+        assert_eq!(tape.len(), 101);
+        assert_eq!(tape.get(100), 7);
+        assert_eq!(tape.get(99), 0);
+    }
+
+    #[test]
+    fn sparse_tape_allocates_touched_pages_only() {
+        let mut tape = SparseTape::default();
+
+        tape.set(1 << 40, 3);
+        tape.set((1 << 40) + 1, 4);
+        tape.set(PAGE_SIZE - 1, 5);
+
+        assert_eq!(tape.pages.len(), 2);
+        assert_eq!(tape.get(1 << 40), 3);
+        assert_eq!(tape.get((1 << 40) + 1), 4);
+        assert_eq!(tape.get(PAGE_SIZE - 1), 5);
+        assert_eq!(tape.get(PAGE_SIZE), 0);
+    }
+
+    /// Checks `find_zero` of tape created by `make_tape` with cells 1..=9 set to one.
+    fn finds_zero<T: Tape>(make_tape: fn() -> T) {
+        let mut tape = make_tape();
+        (1..10).for_each(|t| tape.set(t, 1));
+
+        assert_eq!(tape.find_zero(1, 1), 10);
+        assert_eq!(tape.find_zero(9, -1), 0);
+        assert_eq!(tape.find_zero(1, 3), 10);
+        assert_eq!(tape.find_zero(9, -3), 0);
+        assert_eq!(tape.find_zero(12, 1), 12);
+    }
+
+    #[test]
+    fn all_tapes_find_zero() {
+        finds_zero(|| [0; MAX_MEMORY]);
+        finds_zero(Vec::new);
+        finds_zero(SparseTape::default);
+    }
+
+    #[test]
+    fn vec_tape_treats_cells_past_end_as_zero() {
+        let mut tape = vec![1; 4];
+
+        assert_eq!(tape.find_zero(2, 1), 4);
+        assert_eq!(tape.find_zero(2, 3), 5);
+        tape.push(0);
+        assert_eq!(tape.find_zero(0, 2), 4);
+    }
+
+    #[test]
+    fn interpreter_runs_at_huge_addresses() {
+        let program = Program::from_string("+++[->>++<<]>>.");
+        let mut output = Vec::new();
+        let mut vm = Interpreter::new(&program, std::io::empty(), &mut output)
+            .with_tape(SparseTape::default());
+        vm.memory_pointer = usize::MAX / 2;
+        vm.interpret().unwrap();
+
+        assert_eq!(vm.memory.get(usize::MAX / 2 + 2), 6);
+        assert_eq!(output, vec![6]);
+    }
+}
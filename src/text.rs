@@ -0,0 +1,413 @@
+use std::fmt::{Display, Formatter};
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::brainfuck::Location;
+use crate::ir::{IrCode, IrNode, IrOp, Origin};
+
+/// Error in textual IR.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    pub location: Location,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    /* unsigned number: count, cell value or change of it */
+    Value(u16),
+    /* number with explicit sign: offset of cell from the pointer */
+    Offset(i32),
+    /* number prefixed by `*`: factor of mulcopy */
+    Factor(u8),
+    Bytes(Vec<u8>),
+    Comma,
+    Open,
+    Close,
+}
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    location: Location,
+}
+
+impl<'a> Lexer<'a> {
+    fn error<T>(&self, message: String) -> Result<T, ParseError> {
+        Err(ParseError { location: self.location, message })
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.location = Location { line: self.location.line + 1, column: 1 };
+        } else {
+            self.location.column += 1;
+        }
+        Some(c)
+    }
+
+    fn digits(&mut self) -> String {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.peek().copied().filter(|c| c.is_ascii_digit()) {
+            digits.push(c);
+            self.bump();
+        }
+        digits
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, ParseError> {
+        let location = self.location;
+        let digits = self.digits();
+        digits.parse().map_err(|_| ParseError { location, message: format!("invalid {} `{}`", what, digits) })
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, ParseError> {
+        let mut bytes = Vec::new();
+        loop {
+            let c = match self.bump() {
+                Some('"') => return Ok(bytes),
+                Some('\\') => match self.bump() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some(c @ '\\') | Some(c @ '"') => c,
+                    Some('x') => {
+                        let hex: String = (0..2).filter_map(|_| self.bump()).collect();
+                        match u8::from_str_radix(&hex, 16) {
+                            Ok(byte) => { bytes.push(byte); continue; }
+                            Err(_) => return self.error(format!("invalid escape `\\x{}`", hex)),
+                        }
+                    }
+                    c => return self.error(format!("invalid escape `\\{}`", c.map(String::from).unwrap_or_default())),
+                },
+                Some(c) if c.is_ascii() => c,
+                Some(c) => return self.error(format!("non-ascii character `{}` must be escaped", c)),
+                None => return self.error("unterminated string".to_owned()),
+            };
+            bytes.push(c as u8);
+        }
+    }
+
+    /// Returns next token and its location, `None` at the end of text.
+    fn next_token(&mut self) -> Result<Option<(Token, Location)>, ParseError> {
+        loop {
+            match self.chars.peek() {
+                Some('#') => while self.chars.peek().is_some_and(|c| *c != '\n') { self.bump(); },
+                Some(c) if c.is_whitespace() => { self.bump(); }
+                _ => break,
+            }
+        }
+
+        let location = self.location;
+        let c = match self.chars.peek() {
+            Some(c) => *c,
+            None => return Ok(None),
+        };
+
+        let token = match c {
+            ',' | '{' | '}' | '"' | '*' | '+' | '-' => {
+                self.bump();
+                match c {
+                    ',' => Token::Comma,
+                    '{' => Token::Open,
+                    '}' => Token::Close,
+                    '"' => Token::Bytes(self.bytes()?),
+                    '*' => Token::Factor(self.number("factor")?),
+                    _ => {
+                        let offset: i32 = self.number("offset")?;
+                        Token::Offset(if c == '-' { -offset } else { offset })
+                    }
+                }
+            }
+            c if c.is_ascii_digit() => Token::Value(self.number("value")?),
+            c if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.peek().copied().filter(|c| c.is_ascii_alphanumeric()) {
+                    word.push(c);
+                    self.bump();
+                }
+                Token::Word(word)
+            }
+            c => return self.error(format!("unexpected character `{}`", c)),
+        };
+
+        Ok(Some((token, location)))
+    }
+}
+
+/// Parser of textual IR with one token of lookahead.
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<(Token, Location)>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Result<Option<&Token>, ParseError> {
+        if self.peeked.is_none() {
+            self.peeked = self.lexer.next_token()?;
+        }
+        Ok(self.peeked.as_ref().map(|t| &t.0))
+    }
+
+    fn advance(&mut self) -> Result<Option<(Token, Location)>, ParseError> {
+        self.peek()?;
+        Ok(self.peeked.take())
+    }
+
+    /// Parses ops until `}` (inside loop) or the end of text (outside of any loop).
+    fn block(&mut self, nested: bool) -> Result<Vec<IrNode>, ParseError> {
+        let mut block = Vec::new();
+
+        loop {
+            let (token, location) = match self.advance()? {
+                Some(t) => t,
+                None if nested => return self.lexer.error("missing `}`".to_owned()),
+                None => return Ok(block),
+            };
+
+            let word = match token {
+                Token::Close if nested => return Ok(block),
+                Token::Word(word) => word,
+                token => return Err(ParseError { location, message: format!("expected op, found {}", describe(&token)) }),
+            };
+
+            let mut operands = Vec::new();
+            if let Some(Token::Value(_)) | Some(Token::Offset(_)) | Some(Token::Factor(_)) | Some(Token::Bytes(_)) = self.peek()? {
+                operands.push(self.advance()?.unwrap().0);
+                while let Some(Token::Comma) = self.peek()? {
+                    self.advance()?;
+                    match self.advance()? {
+                        Some((Token::Comma, location)) | Some((Token::Open, location)) | Some((Token::Close, location))
+                        | Some((Token::Word(_), location)) => return Err(ParseError { location, message: "expected operand".to_owned() }),
+                        Some((operand, _)) => operands.push(operand),
+                        None => return self.lexer.error("expected operand".to_owned()),
+                    }
+                }
+            }
+
+            let op = match (word.as_str(), operands.as_slice()) {
+                ("move", [Token::Offset(x)]) => IrOp::Move(*x),
+                ("add", [Token::Value(x @ 0..=256)]) => IrOp::Add(0, *x as i16),
+                ("add", [Token::Offset(offset), Token::Value(x @ 0..=256)]) => IrOp::Add(*offset, *x as i16),
+                ("sub", [Token::Value(x @ 0..=256)]) => IrOp::Add(0, -(*x as i16)),
+                ("sub", [Token::Offset(offset), Token::Value(x @ 0..=256)]) => IrOp::Add(*offset, -(*x as i16)),
+                ("set", [Token::Value(x @ 0..=255)]) => IrOp::SetIndirect(0, *x as u8),
+                ("set", [Token::Offset(offset), Token::Value(x @ 0..=255)]) => IrOp::SetIndirect(*offset, *x as u8),
+                ("mulcopy", [Token::Offset(target), Token::Factor(factor)]) => IrOp::MulCopy(0, *target, *factor),
+                ("mulcopy", [Token::Offset(source), Token::Offset(target), Token::Factor(factor)]) =>
+                    IrOp::MulCopy(*source, *target, *factor),
+                ("iterations", [Token::Value(step @ 0..=255)]) => IrOp::Iterations(0, *step as u8),
+                ("iterations", [Token::Offset(offset), Token::Value(step @ 0..=255)]) => IrOp::Iterations(*offset, *step as u8),
+                ("write", []) => IrOp::Write(0),
+                ("write", [Token::Offset(offset)]) => IrOp::Write(*offset),
+                ("write", [Token::Bytes(bytes)]) => IrOp::WriteConst(bytes.clone()),
+                ("read", []) => IrOp::Read(0),
+                ("read", [Token::Offset(offset)]) => IrOp::Read(*offset),
+                ("scanright", [Token::Value(stride @ 0..=255)]) => IrOp::ScanRight(*stride as u8),
+                ("scanleft", [Token::Value(stride @ 0..=255)]) => IrOp::ScanLeft(*stride as u8),
+                ("loop", []) | ("if", []) => {
+                    match self.advance()? {
+                        Some((Token::Open, _)) => {}
+                        _ => return Err(ParseError { location, message: format!("expected `{{` after `{}`", word) }),
+                    }
+                    let body = self.block(true)?;
+                    if word == "loop" { IrOp::Loop(body) } else { IrOp::IfNonZero(body) }
+                }
+                ("move", _) | ("add", _) | ("sub", _) | ("set", _) | ("mulcopy", _) | ("iterations", _)
+                | ("write", _) | ("read", _) | ("scanright", _) | ("scanleft", _) | ("loop", _) | ("if", _) =>
+                    return Err(ParseError { location, message: format!("invalid operands of `{}`", word) }),
+                _ => return Err(ParseError { location, message: format!("unknown op `{}`", word) }),
+            };
+
+            block.push(IrNode { op, origin: Origin { location, ..Origin::default() } });
+        }
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("`{}`", word),
+        Token::Value(x) => format!("`{}`", x),
+        Token::Offset(x) => format!("`{:+}`", x),
+        Token::Factor(x) => format!("`*{}`", x),
+        Token::Bytes(_) => "string".to_owned(),
+        Token::Comma => "`,`".to_owned(),
+        Token::Open => "`{`".to_owned(),
+        Token::Close => "`}`".to_owned(),
+    }
+}
+
+/// Writes `bytes` as a quoted string, bytes other than printable ASCII are escaped.
+fn fmt_bytes(f: &mut Formatter, bytes: &[u8]) -> std::fmt::Result {
+    f.write_str("\"")?;
+    for byte in bytes {
+        match byte {
+            b'\n' => f.write_str("\\n")?,
+            b'\t' => f.write_str("\\t")?,
+            b'\r' => f.write_str("\\r")?,
+            b'\\' | b'"' => write!(f, "\\{}", *byte as char)?,
+            0x20..=0x7e => write!(f, "{}", *byte as char)?,
+            _ => write!(f, "\\x{:02x}", byte)?,
+        }
+    }
+    f.write_str("\"")
+}
+
+/// Writes operands of op addressing cell at `offset`, which is left out when zero.
+fn fmt_cell(f: &mut Formatter, name: &str, offset: i32, value: impl Display) -> std::fmt::Result {
+    match offset {
+        0 => write!(f, "{} {}", name, value),
+        _ => write!(f, "{} {:+}, {}", name, offset, value),
+    }
+}
+
+/// Writes `op` on one line, bodies of loops on following lines indented by `depth + 1`.
+fn fmt_op(f: &mut Formatter, op: &IrOp, depth: usize) -> std::fmt::Result {
+    match op {
+        IrOp::Move(x) => write!(f, "move {:+}", x),
+        IrOp::Add(offset, x) if *x < 0 => fmt_cell(f, "sub", *offset, x.unsigned_abs()),
+        IrOp::Add(offset, x) => fmt_cell(f, "add", *offset, *x),
+        IrOp::SetIndirect(offset, x) => fmt_cell(f, "set", *offset, *x),
+        IrOp::MulCopy(0, target, factor) => write!(f, "mulcopy {:+}, *{}", target, factor),
+        IrOp::MulCopy(source, target, factor) => write!(f, "mulcopy {:+}, {:+}, *{}", source, target, factor),
+        IrOp::Iterations(offset, step) => fmt_cell(f, "iterations", *offset, *step),
+        IrOp::Write(0) => f.write_str("write"),
+        IrOp::Write(offset) => write!(f, "write {:+}", offset),
+        IrOp::WriteConst(bytes) => {
+            f.write_str("write ")?;
+            fmt_bytes(f, bytes)
+        }
+        IrOp::Read(0) => f.write_str("read"),
+        IrOp::Read(offset) => write!(f, "read {:+}", offset),
+        IrOp::ScanRight(x) => write!(f, "scanright {}", x),
+        IrOp::ScanLeft(x) => write!(f, "scanleft {}", x),
+        IrOp::Loop(body) | IrOp::IfNonZero(body) => {
+            f.write_str(if let IrOp::Loop(_) = op { "loop {\n" } else { "if {\n" })?;
+            fmt_block(f, body, depth + 1)?;
+            write!(f, "{}}}", "    ".repeat(depth))
+        }
+    }
+}
+
+fn fmt_block(f: &mut Formatter, block: &[IrNode], depth: usize) -> std::fmt::Result {
+    for node in block {
+        f.write_str(&"    ".repeat(depth))?;
+        fmt_op(f, &node.op, depth)?;
+        f.write_str("\n")?;
+    }
+    Ok(())
+}
+
+/// Op in textual IR.
+impl Display for IrOp {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        fmt_op(f, self, 0)
+    }
+}
+
+/// Textual IR, one op per line and bodies of loops indented. It can be parsed back
+/// by `IrCode::parse`.
+impl Display for IrCode {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        fmt_block(f, &self.ops, 0)
+    }
+}
+
+impl IrCode {
+    /// Parses textual IR as printed by `Display`. Ops originate from their location
+    /// in the text.
+    pub fn parse(text: &str) -> Result<IrCode, ParseError> {
+        let lexer = Lexer { chars: text.chars().peekable(), location: Location { line: 1, column: 1 } };
+        let ops = Parser { lexer, peeked: None }.block(false)?;
+        Ok(IrCode::from_ops(ops))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matches::assert_matches;
+
+    use crate::brainfuck::{Program, Location, Overflow};
+    use crate::compiler::{IoFn, set_buffered_input, take_buffered_output};
+    use crate::eval::Evaluator;
+    use crate::ir::{IrCode, IrOp};
+
+    #[test]
+    fn prints_ops() {
+        let mut ir_code = IrCode::new(&Program::from_string(",[->++>+++<<]>>[>]<<<[.[-]]"));
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+        ir_code.ops.insert(0, IrCode::parse("write \"a\\n\\\"\\x00\"").unwrap().ops.remove(0));
+
+        assert_eq!(ir_code.to_string(), "write \"a\\n\\\"\\x00\"\nread\nmulcopy +1, *2\nmulcopy +2, *3\nset 0\nmove +2\n\
+            scanright 1\nmove -3\nif {\n    write\n    set 0\n}\n");
+    }
+
+    #[test]
+    fn parses_ops() {
+        let ir_code = IrCode::parse("# comment\nadd 3 sub -2, 255\nmulcopy -1, +2, *3 loop {\n  iterations +1, 4\n}\n\
+            write \"\\t\\\\\" read +1").unwrap();
+        let mut iter = ir_code.iter().map(|t| &t.op);
+
+        assert_matches!(iter.next(), Some(IrOp::Add(0, 3)));
+        assert_matches!(iter.next(), Some(IrOp::Add(-2, -255)));
+        assert_matches!(iter.next(), Some(IrOp::MulCopy(-1, 2, 3)));
+        assert_matches!(iter.next(), Some(IrOp::Loop(_)));
+        assert_matches!(iter.next(), Some(IrOp::Iterations(1, 4)));
+        assert_matches!(iter.next(), Some(IrOp::WriteConst(bytes)) if bytes == b"\t\\");
+        assert_matches!(iter.next(), Some(IrOp::Read(1)));
+        assert_matches!(iter.next(), None);
+        assert_eq!(ir_code.ops[2].origin.location, Location { line: 3, column: 1 });
+        assert_eq!(IrCode::parse("sub 256").unwrap().to_string(), "sub 256\n");
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let error = |text| IrCode::parse(text).err().unwrap().to_string();
+
+        assert_eq!(error("add 1\n  jump 3"), "2:3: unknown op `jump`");
+        assert_eq!(error("add +1"), "1:1: invalid operands of `add`");
+        assert_eq!(error("set 256"), "1:1: invalid operands of `set`");
+        assert_eq!(error("sub 257"), "1:1: invalid operands of `sub`");
+        assert_eq!(error("set 70000"), "1:5: invalid value `70000`");
+        assert_eq!(error("loop {\nadd 1"), "2:6: missing `}`");
+        assert_eq!(error("}"), "1:1: expected op, found `}`");
+        assert_eq!(error("loop add 1"), "1:1: expected `{` after `loop`");
+        assert_eq!(error("write \"\\q\""), "1:10: invalid escape `\\q`");
+    }
+
+    #[test]
+    fn parses_printed_programs() {
+        for file in &["hello_world.bf", "pi.bf", "sierpinski.bf", "squares.bf", "hanoi.bf"] {
+            let path = format!("{}/sample_programs/{}", env!("CARGO_MANIFEST_DIR"), file);
+            let program = Program::from_string(&std::fs::read_to_string(path).unwrap());
+            let mut ir_code = IrCode::new(&program);
+            ir_code.optimize();
+
+            let text = ir_code.to_string();
+            assert_eq!(IrCode::parse(&text).unwrap().to_string(), text, "{}", file);
+        }
+    }
+
+    #[test]
+    fn runs_parsed_ir() {
+        let text = "read\nloop {\n    write\n    mulcopy +1, *2\n    set 0\n}\nwrite +1\nwrite \"!\"\n";
+        let mut ir_code = IrCode::parse(text).unwrap();
+
+        set_buffered_input(b"A".to_vec());
+        ir_code.compile(IoFn::buffered()).execute().unwrap();
+        assert_eq!(take_buffered_output(), b"A\x82!");
+
+        let mut evaluator = Evaluator::new(Overflow::Wrap, usize::MAX).with_input(b"A".to_vec());
+        evaluator.execute_block(&ir_code.ops).unwrap();
+        assert_eq!(evaluator.output, b"A\x82!");
+    }
+}
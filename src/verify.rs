@@ -0,0 +1,320 @@
+use std::fmt::{Display, Formatter};
+use crate::brainfuck::{Program, Op, Overflow, RuntimeError, MAX_MEMORY};
+use crate::interpreter::Interpreter;
+use crate::observer::{ExecutionObserver, Io};
+use crate::ir::{IrCode, IrOp, Optimizations};
+use crate::compiler::{IoFn, set_buffered_input, take_buffered_output};
+
+/// Observable result of running a program.
+pub struct Run {
+    pub output: Vec<u8>,
+    pub memory: Vec<u8>,
+    pub result: Result<(), RuntimeError>,
+}
+
+/// First observable difference between two runs of the same program.
+#[derive(Debug, Eq, PartialEq)]
+pub enum Difference {
+    /* `None` means that output ended before this byte */
+    Output { index: usize, expected: Option<u8>, actual: Option<u8> },
+    Result { expected: Result<(), RuntimeError>, actual: Result<(), RuntimeError> },
+    Memory { address: usize, expected: u8, actual: u8 },
+}
+
+/// Part of program around the instruction which wrote the first differing byte.
+#[derive(Debug)]
+pub struct Region {
+    pub position: usize,
+    pub first: usize,
+    pub last: usize,
+    pub optimizations: Optimizations,
+}
+
+/// Difference of one JIT run compared to the interpreter.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub backend: &'static str,
+    pub difference: Difference,
+    pub region: Option<Region>,
+}
+
+/// Records instruction which wrote each byte of output.
+struct OutputPositions(Vec<usize>);
+
+impl ExecutionObserver for OutputPositions {
+    fn on_io(&mut self, position: usize, io: Io) {
+        if let Io::Write(_) = io {
+            self.0.push(position);
+        }
+    }
+}
+
+/// Runs the program with the plain interpreter. Also returns position of instruction
+/// which wrote each byte of output.
+pub fn run_interpreter(program: &Program, input: &[u8], overflow: Overflow) -> (Run, Vec<usize>) {
+    let mut output = Vec::new();
+    let mut vm = Interpreter::new(program, input, &mut output)
+        .with_observer(OutputPositions(Vec::new()));
+    vm.overflow = overflow;
+
+    let result = vm.interpret();
+    let memory = vm.memory.to_vec();
+    let positions = vm.observer.0;
+
+    (Run { output, memory, result }, positions)
+}
+
+/// Compiles and runs the program with the JIT.
+pub fn run_jit(ir_code: &mut IrCode, input: &[u8]) -> Run {
+    let brainfuck = ir_code.compile(IoFn::buffered());
+    let mut memory = vec![0; MAX_MEMORY];
+
+    set_buffered_input(input.to_vec());
+    take_buffered_output();
+    let result = brainfuck.execute_with_memory(&mut memory);
+
+    Run { output: take_buffered_output(), memory, result }
+}
+
+/// Returns the first difference of `actual` run from `expected` one.
+pub fn compare(expected: &Run, actual: &Run) -> Option<Difference> {
+    let len = expected.output.len().max(actual.output.len());
+    if let Some(index) = (0..len).find(|i| expected.output.get(*i) != actual.output.get(*i)) {
+        return Some(Difference::Output {
+            index,
+            expected: expected.output.get(index).copied(),
+            actual: actual.output.get(index).copied(),
+        });
+    }
+
+    if expected.result != actual.result {
+        return Some(Difference::Result { expected: expected.result, actual: actual.result });
+    }
+
+    expected.memory.iter()
+        .zip(actual.memory.iter())
+        .position(|(e, a)| e != a)
+        .map(|address| Difference::Memory { address, expected: expected.memory[address], actual: actual.memory[address] })
+}
+
+/// Finds the innermost loop around instruction at `position` (or the part of program
+/// before it when it is not inside a loop) and optimizations applied to ops created from it.
+pub fn blame(program: &Program, ir_code: &IrCode, position: usize) -> Region {
+    let mut level = 0;
+    let loop_start = (0..position).rev().find(|idx| {
+        match program.instructions[*idx] {
+            Op::JumpForward if level == 0 => return true,
+            Op::JumpForward => level -= 1,
+            Op::JumpBackward => level += 1,
+            _ => ()
+        }
+        false
+    });
+
+    let (first, last) = match loop_start {
+        Some(start) => (start, program.find_matching_jump_end(start)),
+        None => (0, position),
+    };
+
+    let optimizations = ir_code.iter()
+        .map(|node| node.origin)
+        .filter(|origin| origin.first <= last && origin.last >= first)
+        .fold(Optimizations::empty(), |acc, origin| acc | origin.optimizations);
+
+    Region { position, first, last, optimizations }
+}
+
+/// Runs the program with the interpreter, unoptimized JIT and JIT optimized by
+/// `passes` and returns all differences of JIT runs from the interpreter. When the
+/// interpreter finds an infinite loop, only a JIT which stops in it too is run.
+pub fn verify(program: &Program, input: &[u8], overflow: Overflow, passes: Optimizations) -> Vec<Mismatch> {
+    let (expected, positions) = run_interpreter(program, input, overflow);
+    let mut mismatches = Vec::new();
+
+    for (backend, optimize) in [("unoptimized jit", false), ("optimized jit", true)].iter() {
+        let mut ir_code = IrCode::new(program);
+        ir_code.overflow = overflow;
+        if *optimize {
+            ir_code.passes = passes;
+            ir_code.optimize();
+        }
+
+        /* JIT only stops in counter loops which never finish, other infinite loops hang it */
+        if let Err(RuntimeError::InfiniteLoop(location)) = expected.result {
+            if !ir_code.iter().any(|node| matches!(node.op, IrOp::Iterations(..)) && node.origin.location == location) {
+                continue;
+            }
+        }
+
+        let actual = run_jit(&mut ir_code, input);
+
+        if let Some(difference) = compare(&expected, &actual) {
+            let region = match difference {
+                Difference::Output { index, .. } if *optimize => {
+                    /* when JIT wrote extra bytes, blame the last byte written by interpreter */
+                    positions.get(index).or_else(|| positions.last())
+                        .map(|position| blame(program, &ir_code, *position))
+                }
+                _ => None,
+            };
+
+            mismatches.push(Mismatch { backend, difference, region });
+        }
+    }
+
+    mismatches
+}
+
+/// Describes `Mismatch` using source locations of `program`.
+pub struct Report<'a>(pub &'a Program, pub &'a Mismatch);
+
+impl<'a> Display for Report<'a> {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let Report(program, mismatch) = self;
+        let byte = |b: Option<u8>| b.map(|b| format!("{:#04x}", b)).unwrap_or_else(|| "end of output".to_owned());
+
+        write!(f, "{}: ", mismatch.backend)?;
+        match mismatch.difference {
+            Difference::Output { index, expected, actual } =>
+                write!(f, "output differs at byte {}: expected {}, got {}", index, byte(expected), byte(actual))?,
+            Difference::Result { expected, actual } =>
+                write!(f, "result differs: expected {:?}, got {:?}", expected, actual)?,
+            Difference::Memory { address, expected, actual } =>
+                write!(f, "memory differs at cell {}: expected {}, got {}", address, expected, actual)?,
+        }
+
+        if let Some(region) = &mismatch.region {
+            let names = region.optimizations.names();
+            write!(f, "\n  output was written by instruction at {} in region {}-{}",
+                   program.locations[region.position], program.locations[region.first], program.locations[region.last])?;
+            write!(f, "\n  optimizations touching this region: {}",
+                   if names.is_empty() { "none".to_owned() } else { names.join(", ") })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::brainfuck::{Program, Overflow, RuntimeError, Location};
+    use crate::ir::{IrCode, IrOp, Optimizations};
+    use crate::verify::{verify, run_interpreter, run_jit, compare, blame, Difference, Report, Mismatch};
+
+    #[test]
+    fn sample_program_has_no_mismatches() {
+        let program = Program::from_string(",[>+++[>++<-]>.[-]<<.,]>[-]+++[->++<]>.");
+
+        assert!(verify(&program, b"abc\0", Overflow::Wrap, Optimizations::all()).is_empty());
+        assert!(verify(&program, b"abc\0", Overflow::Trap, Optimizations::all()).is_empty());
+    }
+
+    #[test]
+    fn compares_trapping_runs() {
+        /* unoptimized JIT stops at the same instruction with the same memory */
+        let program = Program::from_string("+++>-<+");
+        let (expected, _) = run_interpreter(&program, b"", Overflow::Trap);
+        let mut ir_code = IrCode::new(&program);
+        ir_code.overflow = Overflow::Trap;
+        let actual = run_jit(&mut ir_code, b"");
+        assert_eq!(actual.memory[..2], [3, 0]);
+        assert_eq!(compare(&expected, &actual), None);
+
+        /* optimized JIT reports the instruction of the fused run which overflowed */
+        let program = Program::from_string("+.>+--<+++>");
+        let mismatches = verify(&program, b"", Overflow::Trap, Optimizations::all());
+        assert!(mismatches.is_empty(), "{:?}", mismatches);
+    }
+
+    #[test]
+    fn compares_counter_loops_which_never_finish() {
+        let program = Program::from_string(",[-->+<]");
+
+        assert!(verify(&program, &[3], Overflow::Wrap, Optimizations::all()).is_empty());
+        assert!(verify(&program, &[4], Overflow::Wrap, Optimizations::all()).is_empty());
+    }
+
+    #[test]
+    fn compares_trap_locations_of_fused_runs() {
+        /* both stop at the `+` which overflowed, in the middle of the fused run */
+        let program = Program::from_string(",>+<+++");
+        let (expected, _) = run_interpreter(&program, &[254], Overflow::Trap);
+        let mut ir_code = IrCode::new(&program);
+        ir_code.overflow = Overflow::Trap;
+        ir_code.eval_budget = 0;
+        ir_code.optimize();
+
+        let actual = run_jit(&mut ir_code, &[254]);
+        assert_eq!(actual.result, Err(RuntimeError::Overflow(Location { line: 1, column: 6 })));
+        assert_eq!(compare(&expected, &actual), None);
+
+        /* a different location is reported */
+        let mut actual = run_jit(&mut ir_code, &[254]);
+        actual.result = Err(RuntimeError::Overflow(Location { line: 1, column: 5 }));
+        assert!(matches!(compare(&expected, &actual), Some(Difference::Result { .. })));
+    }
+
+    #[test]
+    fn finds_first_differing_byte() {
+        let program = Program::from_string("+.+.+.");
+        let (expected, _) = run_interpreter(&program, b"", Overflow::Wrap);
+
+        let mut ir_code = IrCode::new(&program);
+        ir_code.ops[2].op = IrOp::Add(0, 2);
+        let actual = run_jit(&mut ir_code, b"");
+
+        assert_eq!(compare(&expected, &actual), Some(Difference::Output { index: 1, expected: Some(2), actual: Some(3) }));
+    }
+
+    #[test]
+    fn finds_differing_results_and_memory() {
+        let program = Program::from_string("->+");
+        let (expected, _) = run_interpreter(&program, b"", Overflow::Trap);
+
+        let mut ir_code = IrCode::new(&program);
+        let actual = run_jit(&mut ir_code, b"");
+
+        assert_eq!(compare(&expected, &actual), Some(Difference::Result {
+            expected: Err(RuntimeError::Underflow(Location { line: 1, column: 1 })),
+            actual: Ok(()),
+        }));
+
+        let (expected, _) = run_interpreter(&program, b"", Overflow::Wrap);
+        ir_code.ops[2].op = IrOp::Add(0, 2);
+        let actual = run_jit(&mut ir_code, b"");
+
+        assert_eq!(compare(&expected, &actual), Some(Difference::Memory { address: 1, expected: 1, actual: 2 }));
+    }
+
+    #[test]
+    fn blames_optimizations_of_enclosing_loop() {
+        let program = Program::from_string("++>+<[->+++<]>[.-]>+++.");
+        let mut ir_code = IrCode::new(&program);
+        ir_code.eval_budget = 0;
+        ir_code.passes -= Optimizations::UNROLL;
+        ir_code.optimize();
+
+        let region = blame(&program, &ir_code, 15);
+        assert_eq!((region.first, region.last), (14, 17));
+        assert_eq!(region.optimizations, Optimizations::empty());
+
+        let region = blame(&program, &ir_code, 22);
+        assert_eq!((region.first, region.last), (0, 22));
+        assert_eq!(region.optimizations, Optimizations::CONSECUTIVE | Optimizations::MULTIPLICATION_LOOP | Optimizations::OFFSET_ADDRESSING
+            | Optimizations::CONSTANT_PROPAGATION);
+    }
+
+    #[test]
+    fn reports_mismatch() {
+        let program = Program::from_string("+\n[>+<-]>.");
+        let mismatch = Mismatch {
+            backend: "optimized jit",
+            difference: Difference::Output { index: 0, expected: Some(1), actual: None },
+            region: Some(crate::verify::Region { position: 8, first: 0, last: 8, optimizations: Optimizations::MULTIPLICATION_LOOP }),
+        };
+
+        assert_eq!(Report(&program, &mismatch).to_string(), "optimized jit: output differs at byte 0: expected 0x01, got end of output\n  \
+            output was written by instruction at 2:8 in region 1:1-2:8\n  \
+            optimizations touching this region: multiplication-loop");
+    }
+}
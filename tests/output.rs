@@ -0,0 +1,26 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Runs brainfuck `source` with the JIT at optimization `level`, returns its output.
+fn run(source: &str, level: &str) -> Vec<u8> {
+    let path = std::env::temp_dir().join(format!("bfjit-output-{}-{}.bf", std::process::id(), level));
+    std::fs::File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_bfjit"))
+        .args(["run", "-O", level, path.to_str().unwrap()])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    output.stdout
+}
+
+#[test]
+fn writes_raw_bytes_at_every_level() {
+    /* -O0 writes the cell, -O3 writes the folded constant */
+    let source = format!("{}.", "+".repeat(200));
+
+    assert_eq!(run(&source, "0"), [200]);
+    assert_eq!(run(&source, "3"), [200]);
+}